@@ -1,10 +1,13 @@
 //! Example shopkeep server using runway.
 
 use std::path::PathBuf;
+#[cfg(feature = "sqlite")]
+use std::sync::Arc;
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use runway::{Module, Router};
-use shopkeep::ExtensionModule;
+use shopkeep::auth::NewToken;
+use shopkeep::{ExtensionModule, Scope, TokenStore};
 use tracing::info;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -23,6 +26,162 @@ struct Args {
     /// Port number
     #[arg(short, long, default_value = "8080")]
     port: u16,
+
+    /// Metadata backend
+    #[arg(long, value_enum, default_value = "filesystem")]
+    backend: Backend,
+
+    /// TOML file of curated categories; published packages must use only these
+    #[arg(long)]
+    categories: Option<PathBuf>,
+
+    #[cfg(all(feature = "sqlite", feature = "s3"))]
+    #[command(flatten)]
+    s3: S3Args,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Package storage in an S3-compatible bucket, used by the sqlite backend. Credentials are read
+/// from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN`.
+#[cfg(all(feature = "sqlite", feature = "s3"))]
+#[derive(clap::Args, Debug)]
+struct S3Args {
+    /// Store packages in this bucket instead of under --registry-path
+    #[arg(long)]
+    s3_bucket: Option<String>,
+
+    /// S3 endpoint, e.g. http://localhost:9000 for MinIO
+    #[arg(long, default_value = "https://s3.amazonaws.com")]
+    s3_endpoint: String,
+
+    /// S3 region
+    #[arg(long, default_value = "us-east-1")]
+    s3_region: String,
+
+    /// Prefix for object keys
+    #[arg(long, default_value = "")]
+    s3_prefix: String,
+
+    /// Use virtual-hosted-style bucket addressing instead of path-style
+    #[arg(long)]
+    s3_virtual_hosted: bool,
+
+    /// Redirect downloads to presigned URLs valid for this many seconds
+    #[arg(long)]
+    s3_presign_secs: Option<u64>,
+}
+
+#[cfg(all(feature = "sqlite", feature = "s3"))]
+impl S3Args {
+    fn blobs(&self) -> anyhow::Result<Option<Arc<dyn shopkeep::BlobStore>>> {
+        use shopkeep::registry::s3::{S3BlobStore, S3Config};
+
+        let Some(bucket) = &self.s3_bucket else {
+            return Ok(None);
+        };
+        let mut config = S3Config::new(&self.s3_endpoint, bucket)
+            .with_credentials(std::env::var("AWS_ACCESS_KEY_ID")?, std::env::var("AWS_SECRET_ACCESS_KEY")?);
+        config.session_token = std::env::var("AWS_SESSION_TOKEN").ok();
+        config.region = self.s3_region.clone();
+        config.prefix = self.s3_prefix.clone();
+        config.path_style = !self.s3_virtual_hosted;
+        config.presign_expiry = self.s3_presign_secs.map(std::time::Duration::from_secs);
+        Ok(Some(Arc::new(S3BlobStore::new(config)?)))
+    }
+}
+
+/// Contents of the `--categories` file.
+#[derive(serde::Deserialize)]
+struct CategoriesFile {
+    #[serde(default)]
+    category: Vec<shopkeep::Category>,
+}
+
+fn load_categories(path: &std::path::Path) -> anyhow::Result<Vec<shopkeep::Category>> {
+    let file: CategoriesFile = toml::from_str(&std::fs::read_to_string(path)?)?;
+    Ok(file.category)
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Backend {
+    Filesystem,
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage API tokens
+    #[command(subcommand)]
+    Token(TokenCommand),
+
+    /// Re-hash every stored package and report versions whose package is missing or damaged
+    Verify,
+
+    /// Import a filesystem registry into the SQLite index at --registry-path
+    #[cfg(feature = "sqlite")]
+    Import {
+        /// Root of the filesystem registry to import (defaults to --registry-path, importing in place)
+        #[arg(long)]
+        from: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum TokenCommand {
+    /// Mint a new token and print its secret
+    Create {
+        /// Unique label for the token
+        #[arg(long)]
+        name: String,
+
+        /// Owner the token publishes as; tokens of the same owner share its extensions
+        #[arg(long)]
+        owner: String,
+
+        /// Granted scope (publish, yank, admin); repeatable
+        #[arg(long = "scope", required = true)]
+        scopes: Vec<Scope>,
+
+        /// Restrict the token to an extension id; repeatable
+        #[arg(long = "extension")]
+        extensions: Vec<String>,
+    },
+    /// List tokens
+    List,
+    /// Revoke a token by id
+    Revoke { id: String },
+}
+
+async fn token_command(registry_path: PathBuf, command: TokenCommand) -> anyhow::Result<()> {
+    let tokens = TokenStore::new(registry_path.join("tokens.json"));
+    match command {
+        TokenCommand::Create { name, owner, scopes, extensions } => {
+            let extensions = if extensions.is_empty() { None } else { Some(extensions) };
+            let minted = tokens.create(NewToken { name, owner, scopes, extensions }).await?;
+            println!("{}\t{}", minted.info.id, minted.token);
+        }
+        TokenCommand::List => {
+            for token in tokens.list().await? {
+                let scopes: Vec<_> = token.scopes.iter().map(|s| s.as_str()).collect();
+                println!("{}\t{}\t{}\t{}", token.id, token.name, token.owner, scopes.join(","));
+            }
+        }
+        TokenCommand::Revoke { id } => tokens.revoke(&id).await?,
+    }
+    Ok(())
+}
+
+#[cfg(feature = "sqlite")]
+fn open_sqlite(args: &Args) -> anyhow::Result<shopkeep::SqliteRegistry> {
+    #[cfg(feature = "s3")]
+    if let Some(blobs) = args.s3.blobs()? {
+        info!("Storing packages in S3 bucket {}", args.s3.s3_bucket.as_deref().unwrap_or_default());
+        return Ok(shopkeep::SqliteRegistry::with_blobs(args.registry_path.clone(), blobs)?);
+    }
+    Ok(shopkeep::SqliteRegistry::open(args.registry_path.clone())?)
 }
 
 #[tokio::main]
@@ -32,11 +191,43 @@ async fn main() -> anyhow::Result<()> {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let args = Args::parse();
+    let mut args = Args::parse();
+
+    match args.command.take() {
+        Some(Command::Token(command)) => return token_command(args.registry_path, command).await,
+        #[cfg(feature = "sqlite")]
+        Some(Command::Import { from }) => {
+            let source = shopkeep::FilesystemRegistry::new(from.unwrap_or_else(|| args.registry_path.clone()));
+            let target = open_sqlite(&args)?;
+            let count = target.import(&source).await?;
+            info!("Imported {} versions", count);
+            return Ok(());
+        }
+        Some(Command::Verify) => {
+            let damaged = match args.backend {
+                Backend::Filesystem => shopkeep::FilesystemRegistry::new(args.registry_path.clone()).verify().await?,
+                #[cfg(feature = "sqlite")]
+                Backend::Sqlite => open_sqlite(&args)?.verify().await?,
+            };
+            for version in &damaged {
+                println!("{}@{}\t{}", version.id, version.version, version.reason);
+            }
+            if !damaged.is_empty() {
+                anyhow::bail!("{} damaged versions", damaged.len());
+            }
+            return Ok(());
+        }
+        None => {}
+    }
 
     info!("Starting shopkeep server on {}:{}", args.host, args.port);
     info!("Registry path: {}", args.registry_path.display());
 
+    let categories = match &args.categories {
+        Some(path) => load_categories(path)?,
+        None => Vec::new(),
+    };
+
     let mut router = Router::new();
 
     // Health endpoint
@@ -48,7 +239,16 @@ async fn main() -> anyhow::Result<()> {
     });
 
     // Extension module
-    let ext = ExtensionModule::new(args.registry_path);
+    let ext = match args.backend {
+        Backend::Filesystem => ExtensionModule::new(args.registry_path),
+        #[cfg(feature = "sqlite")]
+        Backend::Sqlite => {
+            let tokens = Arc::new(TokenStore::new(args.registry_path.join("tokens.json")));
+            let registry = open_sqlite(&args)?;
+            ExtensionModule::with_registry(Arc::new(registry)).with_tokens(tokens)
+        }
+    };
+    let ext = ext.with_categories(categories);
     info!("Loading module: {}", ext.name());
     ext.routes(&mut router);
 
@@ -67,7 +267,11 @@ async fn main() -> anyhow::Result<()> {
         },
     };
 
-    runway::server::run(config, None, router.into_handle()).await?;
+    // Return on Ctrl-C rather than exiting, so dropping the registry saves pending download counts.
+    tokio::select! {
+        result = runway::server::run(config, None, router.into_handle()) => result?,
+        _ = tokio::signal::ctrl_c() => info!("Shutting down"),
+    }
 
     Ok(())
 }
@@ -0,0 +1,264 @@
+//! API token authentication for write endpoints.
+//!
+//! Tokens are stored hashed in a JSON file next to the registry. The plaintext
+//! secret is only returned once, when the token is minted.
+
+use std::path::PathBuf;
+
+use jiff::Timestamp;
+use runway::Context;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use tokio::sync::{Mutex, MutexGuard};
+use tracing::info;
+
+use crate::registry::fs::write_atomic;
+use crate::registry::{lock_file, validate_id, validate_slug};
+use crate::{Error, Result};
+
+/// Prefix of every minted token secret.
+const TOKEN_PREFIX: &str = "skp_";
+
+/// Longest accepted owner name.
+const MAX_OWNER_LEN: usize = 64;
+
+/// Permission granted by a token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Publish,
+    Yank,
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Publish => "publish",
+            Scope::Yank => "yank",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for Scope {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "publish" => Ok(Scope::Publish),
+            "yank" => Ok(Scope::Yank),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(Error::BadRequest(format!("Unknown scope: {}", s))),
+        }
+    }
+}
+
+/// Public information about an API token.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Token {
+    pub id: String,
+    /// Unique label for the token.
+    pub name: String,
+    /// Principal the token acts as. Extension ownership is recorded by owner, so several tokens of
+    /// one publisher (say, while rotating secrets) share their extensions.
+    #[serde(default)]
+    pub owner: String,
+    pub scopes: Vec<Scope>,
+    /// Extension ids this token is restricted to. `None` means any extension.
+    #[serde(default)]
+    pub extensions: Option<Vec<String>>,
+    pub created_at: Timestamp,
+}
+
+impl Token {
+    /// Whether the token grants `scope`. Admin tokens grant every scope.
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&Scope::Admin) || self.scopes.contains(&scope)
+    }
+
+    /// Whether the token may act on extension `id`.
+    pub fn allows(&self, id: &str) -> bool {
+        if self.scopes.contains(&Scope::Admin) {
+            return true;
+        }
+        match &self.extensions {
+            Some(ids) => ids.iter().any(|e| e == id),
+            None => true,
+        }
+    }
+}
+
+/// Request body for minting a token.
+#[derive(Debug, Clone, Deserialize)]
+pub struct NewToken {
+    pub name: String,
+    pub owner: String,
+    pub scopes: Vec<Scope>,
+    #[serde(default)]
+    pub extensions: Option<Vec<String>>,
+}
+
+/// A freshly minted token, including its secret.
+#[derive(Debug, Clone, Serialize)]
+pub struct Minted {
+    pub token: String,
+    #[serde(flatten)]
+    pub info: Token,
+}
+
+/// Token as persisted on disk.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Record {
+    #[serde(flatten)]
+    token: Token,
+    hash: String,
+}
+
+/// File-backed token store.
+///
+/// Changes are serialized within the process by a mutex and across processes by an advisory lock on
+/// `<path>.lock`, and written to a uniquely named temporary file that is renamed into place.
+pub struct TokenStore {
+    path: PathBuf,
+    write_lock: Mutex<()>,
+}
+
+impl TokenStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    async fn load(&self) -> Result<Vec<Record>> {
+        let mut records: Vec<Record> = match fs::read_to_string(&self.path).await {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        // Tokens minted before owners existed acted as their name, which is what ownership was
+        // recorded under.
+        for record in &mut records {
+            if record.token.owner.is_empty() {
+                record.token.owner = record.token.name.clone();
+            }
+        }
+        Ok(records)
+    }
+
+    async fn save(&self, records: &[Record]) -> Result<()> {
+        write_atomic(&self.path, serde_json::to_string_pretty(records)?.as_bytes()).await
+    }
+
+    /// Take the write lock, first within this process and then across processes.
+    async fn lock(&self) -> Result<(MutexGuard<'_, ()>, std::fs::File)> {
+        let guard = self.write_lock.lock().await;
+        let mut lock_path = self.path.clone().into_os_string();
+        lock_path.push(".lock");
+        Ok((guard, lock_file(lock_path.into()).await?))
+    }
+
+    /// List all tokens.
+    pub async fn list(&self) -> Result<Vec<Token>> {
+        Ok(self.load().await?.into_iter().map(|r| r.token).collect())
+    }
+
+    /// Mint a new token. The returned secret is not stored and cannot be recovered.
+    pub async fn create(&self, request: NewToken) -> Result<Minted> {
+        if request.name.trim().is_empty() {
+            return Err(Error::BadRequest("Token name must not be empty".into()));
+        }
+        validate_owner(&request.owner)?;
+        if request.scopes.is_empty() {
+            return Err(Error::BadRequest("Token needs at least one scope".into()));
+        }
+        for id in request.extensions.iter().flatten() {
+            validate_id(id)?;
+        }
+
+        let _lock = self.lock().await?;
+        let mut records = self.load().await?;
+        if records.iter().any(|r| r.token.name.eq_ignore_ascii_case(request.name.trim())) {
+            return Err(Error::BadRequest(format!("A token named {:?} already exists", request.name.trim())));
+        }
+
+        let secret = format!("{}{}", TOKEN_PREFIX, hex::encode(rand::random::<[u8; 32]>()));
+        let token = Token {
+            id: hex::encode(rand::random::<[u8; 8]>()),
+            name: request.name.trim().to_string(),
+            owner: request.owner,
+            scopes: request.scopes,
+            extensions: request.extensions,
+            created_at: Timestamp::now(),
+        };
+        records.push(Record {
+            token: token.clone(),
+            hash: hash(&secret),
+        });
+        self.save(&records).await?;
+
+        info!("Created API token {} ({}, acting as {})", token.id, token.name, token.owner);
+        Ok(Minted { token: secret, info: token })
+    }
+
+    /// Revoke the token with the given id.
+    pub async fn revoke(&self, id: &str) -> Result<()> {
+        let _lock = self.lock().await?;
+        let mut records = self.load().await?;
+        let before = records.len();
+        records.retain(|r| r.token.id != id);
+        if records.len() == before {
+            return Err(Error::NotFound(format!("Token {}", id)));
+        }
+        self.save(&records).await?;
+
+        info!("Revoked API token {}", id);
+        Ok(())
+    }
+
+    /// Look up the token matching a plaintext secret.
+    pub async fn authenticate(&self, secret: &str) -> Result<Token> {
+        let hashed = hash(secret);
+        self.load()
+            .await?
+            .into_iter()
+            .find(|r| r.hash == hashed)
+            .map(|r| r.token)
+            .ok_or_else(|| Error::Unauthorized("Invalid API token".into()))
+    }
+
+    /// Authenticate the bearer token on a request and require `scope`.
+    pub async fn authorize(&self, ctx: &Context, scope: Scope) -> Result<Token> {
+        let secret = ctx.request.headers()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .map(|(_, secret)| secret)
+            .ok_or_else(|| Error::Unauthorized("Missing bearer token".into()))?;
+
+        let token = self.authenticate(secret.trim()).await?;
+        if !token.has_scope(scope) {
+            return Err(Error::Forbidden(format!("Token {} lacks the {} scope", token.id, scope)));
+        }
+        Ok(token)
+    }
+}
+
+/// Check that `owner` is a lowercase slug, like extension ids. Owners end up in URLs.
+pub fn validate_owner(owner: &str) -> Result<()> {
+    validate_slug("owner", owner, MAX_OWNER_LEN)
+}
+
+fn hash(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
@@ -2,86 +2,669 @@
 
 use std::sync::Arc;
 
+use base64::Engine;
+use futures_util::TryStreamExt;
+use http::header;
+use http_body::Frame;
+use http_body_util::{BodyExt, Empty, StreamBody};
 use runway::response::HttpResponse;
 use runway::{response, Context};
+use sha2::{Digest, Sha256};
+use tokio_util::io::ReaderStream;
 
-use crate::registry::Registry;
-use crate::types::ListOptions;
+use crate::auth::{validate_owner, NewToken, Scope, Token, TokenStore};
+use crate::package::Manifest;
+use crate::registry::{validate_id, PackageStream, Registry};
+use crate::types::{
+    Category, CategoryCount, Filter, FilterField, ListOptions, PublishOptions, ResolveRequest, Suggestion, Version,
+};
 use crate::Error;
 
+/// Default and maximum number of autocomplete suggestions.
+const DEFAULT_SUGGESTIONS: u32 = 10;
+const MAX_SUGGESTIONS: u32 = 50;
+
+/// Cache policy for metadata, which changes on publish and yank.
+const METADATA_CACHE_CONTROL: &str = "public, max-age=60";
+
+/// Media type of `.empkg` packages, which are gzipped tarballs.
+const PACKAGE_CONTENT_TYPE: &str = "application/gzip";
+
+/// Cache policy for packages addressed by exact version. Published versions are immutable; an admin
+/// overwrite is an explicit escape hatch that already breaks clients pinned to the old package.
+const IMMUTABLE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
 pub async fn list_extensions(ctx: Context, registry: Arc<dyn Registry>) -> crate::Result<HttpResponse> {
-    let query_params = parse_query(ctx.request.uri().query());
+    let query = ctx.request.uri().query();
+    let query_params = parse_query(query);
     let options = ListOptions {
         query: query_params.get("q").cloned(),
-        category: query_params.get("category").cloned(),
+        filters: parse_filters(query),
+        facets: parse_facets(query_params.get("facets"))?,
+        host_version: host_version_param(&query_params)?,
+        sort: query_params.get("sort").map(|s| s.parse()).transpose()?,
+        order: query_params.get("order").map(|o| o.parse()).transpose()?,
         page: query_params.get("page").and_then(|p| p.parse().ok()).unwrap_or(1),
         per_page: query_params.get("per_page").and_then(|p| p.parse().ok()).unwrap_or(20),
+        ..Default::default()
     };
     let result = registry.list(options).await?;
-    response::ok(&result).map_err(|e| Error::Internal(e.to_string()))
+    cached_json(&ctx, &result)
+}
+
+/// Top search matches for autocomplete, as ids and names only.
+pub async fn suggest(ctx: Context, registry: Arc<dyn Registry>) -> crate::Result<HttpResponse> {
+    let query_params = parse_query(ctx.request.uri().query());
+    let query = query_params.get("q").map(|q| q.trim()).unwrap_or_default();
+    if query.is_empty() {
+        return cached_json(&ctx, &Vec::<Suggestion>::new());
+    }
+    let limit = query_params
+        .get("limit")
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(DEFAULT_SUGGESTIONS)
+        .clamp(1, MAX_SUGGESTIONS);
+    let options = ListOptions {
+        query: Some(query.to_string()),
+        host_version: host_version_param(&query_params)?,
+        page: 1,
+        per_page: limit,
+        ..Default::default()
+    };
+    let suggestions: Vec<Suggestion> = registry
+        .list(options)
+        .await?
+        .items
+        .into_iter()
+        .map(|s| Suggestion { id: s.id, name: s.name })
+        .collect();
+    cached_json(&ctx, &suggestions)
 }
 
 pub async fn get_extension(ctx: Context, registry: Arc<dyn Registry>) -> crate::Result<HttpResponse> {
-    let id = ctx.require_param("id").map_err(|e| Error::BadRequest(e.to_string()))?;
+    let id = id_param(&ctx)?;
     let details = registry.get(id).await?;
-    response::ok(&details).map_err(|e| Error::Internal(e.to_string()))
+    cached_json(&ctx, &details)
 }
 
 pub async fn list_versions(ctx: Context, registry: Arc<dyn Registry>) -> crate::Result<HttpResponse> {
-    let id = ctx.require_param("id").map_err(|e| Error::BadRequest(e.to_string()))?;
+    let id = id_param(&ctx)?;
     let versions = registry.get_versions(id).await?;
-    response::ok(&versions).map_err(|e| Error::Internal(e.to_string()))
+    cached_json(&ctx, &versions)
 }
 
 pub async fn get_version(ctx: Context, registry: Arc<dyn Registry>) -> crate::Result<HttpResponse> {
-    let id = ctx.require_param("id").map_err(|e| Error::BadRequest(e.to_string()))?;
+    let id = id_param(&ctx)?;
     let version_str = ctx.require_param("version").map_err(|e| Error::BadRequest(e.to_string()))?;
     let version = semver::Version::parse(version_str).map_err(|e| Error::InvalidVersion(e.to_string()))?;
     let info = registry.get_version(id, &version).await?;
-    response::ok(&info).map_err(|e| Error::Internal(e.to_string()))
+    cached_json(&ctx, &info)
 }
 
 pub async fn download(ctx: Context, registry: Arc<dyn Registry>) -> crate::Result<HttpResponse> {
-    let id = ctx.require_param("id").map_err(|e| Error::BadRequest(e.to_string()))?.to_string();
+    let id = id_param(&ctx)?.to_string();
     let version_str = ctx.require_param("version").map_err(|e| Error::BadRequest(e.to_string()))?;
     let version = semver::Version::parse(version_str).map_err(|e| Error::InvalidVersion(e.to_string()))?;
-    let data = registry.download(&id, &version).await?;
-    Ok(response::binary(data, "application/octet-stream", Some(&format!("{}-{}.empkg", id, version))))
+    let info = registry.get_version(&id, &version).await?;
+    let filename = format!("{}-{}.empkg", id, version);
+    let etag = format!("\"{}\"", info.checksum_sha256);
+    let last_modified = http_date(&info);
+
+    if if_none_match(&ctx, &etag) {
+        return not_modified(&etag, IMMUTABLE_CACHE_CONTROL);
+    }
+
+    // Presigned URLs expire, so the redirect itself must not be cached.
+    if let Some(url) = registry.download_url(&id, &version).await? {
+        let mut resp = response::redirect(&url);
+        resp.headers_mut().insert(header::CACHE_CONTROL, header::HeaderValue::from_static("no-store"));
+        return Ok(resp);
+    }
+
+    let range = if if_range_matches(&ctx, &info, &last_modified) {
+        parse_range(header_str(&ctx, header::RANGE), info.size_bytes)
+    } else {
+        RangeRequest::Full
+    };
+
+    let builder = digest_headers(http::Response::builder(), &info)?
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::ETAG, &etag)
+        .header(header::CACHE_CONTROL, IMMUTABLE_CACHE_CONTROL)
+        .header(header::LAST_MODIFIED, last_modified);
+    match range {
+        RangeRequest::Full => {
+            let package = registry.download_stream(&id, &version).await?;
+            package_response(builder.status(http::StatusCode::OK), package, &filename)
+        }
+        RangeRequest::Partial { start, end } => {
+            let package = registry.download_range(&id, &version, start, end).await?;
+            let builder = builder
+                .status(http::StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end - 1, info.size_bytes));
+            package_response(builder, package, &filename)
+        }
+        RangeRequest::Unsatisfiable => builder
+            .status(http::StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(header::CONTENT_RANGE, format!("bytes */{}", info.size_bytes))
+            .body(empty_body())
+            .map_err(|e| Error::Internal(e.to_string())),
+    }
+}
+
+pub async fn publish(
+    ctx: Context,
+    registry: Arc<dyn Registry>,
+    token: Token,
+    max_size: usize,
+    categories: Arc<Vec<Category>>,
+) -> crate::Result<HttpResponse> {
+    let declared = ctx.request.headers()
+        .get("content-length")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if let Some(size) = declared
+        && size > max_size as u64
+    {
+        return Err(Error::TooLarge { size, limit: max_size as u64 });
+    }
+
+    let body = ctx.request.body().clone();
+    if body.len() > max_size {
+        return Err(Error::TooLarge { size: body.len() as u64, limit: max_size as u64 });
+    }
+    if body.is_empty() {
+        return Err(Error::BadRequest("Empty package body".into()));
+    }
+
+    let manifest = Manifest::from_package(&body)?;
+    if !token.allows(&manifest.id) {
+        return Err(Error::Forbidden(format!("Token {} may not publish {}", token.id, manifest.id)));
+    }
+    check_categories(&manifest, &categories)?;
+
+    let overwrite = parse_query(ctx.request.uri().query())
+        .get("force")
+        .is_some_and(|v| v == "true" || v == "1");
+    if overwrite && !token.has_scope(Scope::Admin) {
+        return Err(Error::Forbidden("Only admin tokens may overwrite an existing version".into()));
+    }
+
+    let options = PublishOptions {
+        publisher: token.owner.clone(),
+        overwrite,
+        admin: token.has_scope(Scope::Admin),
+    };
+    let version = registry.publish(body, &options).await?;
+    response::ok(&version).map_err(|e| Error::Internal(e.to_string()))
+}
+
+pub async fn get_latest_version(ctx: Context, registry: Arc<dyn Registry>) -> crate::Result<HttpResponse> {
+    let id = id_param(&ctx)?;
+    let host_version = host_version_param(&parse_query(ctx.request.uri().query()))?;
+    let latest = registry.get_latest_version(id, host_version.as_ref()).await?;
+    cached_json(&ctx, &latest)
+}
+
+/// Reject categories outside the curated list, if there is one.
+fn check_categories(manifest: &Manifest, curated: &[Category]) -> crate::Result<()> {
+    if curated.is_empty() {
+        return Ok(());
+    }
+    for category in &manifest.categories {
+        if !curated.iter().any(|c| c.id.eq_ignore_ascii_case(category)) {
+            let known: Vec<&str> = curated.iter().map(|c| c.id.as_str()).collect();
+            return Err(Error::InvalidPackage(format!(
+                "Unknown category {:?}: expected one of {}",
+                category,
+                known.join(", ")
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Every category with the number of listed extensions in it. Curated categories come first, in
+/// their configured order; categories used by extensions but not curated follow by id.
+pub async fn list_categories(
+    ctx: Context,
+    registry: Arc<dyn Registry>,
+    curated: Arc<Vec<Category>>,
+) -> crate::Result<HttpResponse> {
+    let options = ListOptions {
+        facets: vec![FilterField::Category],
+        ..Default::default()
+    };
+    let mut counts: Vec<(String, u32)> = registry
+        .list(options)
+        .await?
+        .facets
+        .and_then(|mut facets| facets.remove(&FilterField::Category))
+        .unwrap_or_default()
+        .into_iter()
+        .collect();
+
+    let mut categories = Vec::with_capacity(curated.len() + counts.len());
+    for category in curated.iter() {
+        let count = counts
+            .iter()
+            .filter(|(id, _)| id.eq_ignore_ascii_case(&category.id))
+            .map(|(_, count)| count)
+            .sum();
+        counts.retain(|(id, _)| !id.eq_ignore_ascii_case(&category.id));
+        categories.push(CategoryCount { category: category.clone(), count });
+    }
+    for (id, count) in counts {
+        let category = Category { name: id.clone(), id, description: None };
+        categories.push(CategoryCount { category, count });
+    }
+    cached_json(&ctx, &categories)
 }
 
 pub async fn download_latest(ctx: Context, registry: Arc<dyn Registry>) -> crate::Result<HttpResponse> {
-    let id = ctx.require_param("id").map_err(|e| Error::BadRequest(e.to_string()))?;
-    let latest = registry.get_latest_version(id).await?;
+    let id = id_param(&ctx)?;
+    let host_version = host_version_param(&parse_query(ctx.request.uri().query()))?;
+    let latest = registry.get_latest_version(id, host_version.as_ref()).await?;
     let location = format!("/api/v1/extensions/{}/versions/{}/download", id, latest.version);
-    Ok(response::redirect(&location))
+    let mut resp = response::redirect(&location);
+    resp.headers_mut().insert(header::CACHE_CONTROL, header::HeaderValue::from_static(METADATA_CACHE_CONTROL));
+    Ok(resp)
 }
 
-fn parse_query(query: Option<&str>) -> std::collections::HashMap<String, String> {
-    let mut map = std::collections::HashMap::new();
-    if let Some(q) = query {
-        for part in q.split('&') {
-            if let Some((key, value)) = part.split_once('=') {
-                map.insert(urldecode(key), urldecode(value));
-            }
+pub async fn resolve_dependencies(ctx: Context, registry: Arc<dyn Registry>) -> crate::Result<HttpResponse> {
+    let request: ResolveRequest = serde_json::from_slice(ctx.request.body())
+        .map_err(|e| Error::BadRequest(e.to_string()))?;
+    if request.requirements.is_empty() {
+        return Err(Error::BadRequest("At least one requirement is needed".into()));
+    }
+    for id in request.requirements.keys() {
+        validate_id(id)?;
+    }
+    let resolved = crate::resolve::resolve(registry.as_ref(), &request.requirements).await?;
+    response::ok(&resolved).map_err(|e| Error::Internal(e.to_string()))
+}
+
+/// Optional request body for yanking a version.
+#[derive(Debug, Default, serde::Deserialize)]
+struct YankRequest {
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+pub async fn yank(ctx: Context, registry: Arc<dyn Registry>, token: Token) -> crate::Result<HttpResponse> {
+    let id = id_param(&ctx)?;
+    let version_str = ctx.require_param("version").map_err(|e| Error::BadRequest(e.to_string()))?;
+    let version = semver::Version::parse(version_str).map_err(|e| Error::InvalidVersion(e.to_string()))?;
+    require_owner(&registry, &token, id).await?;
+
+    let body = ctx.request.body();
+    let request: YankRequest = if body.is_empty() {
+        YankRequest::default()
+    } else {
+        serde_json::from_slice(body).map_err(|e| Error::BadRequest(e.to_string()))?
+    };
+    let info = registry.yank(id, &version, request.reason).await?;
+    response::ok(&info).map_err(|e| Error::Internal(e.to_string()))
+}
+
+pub async fn unyank(ctx: Context, registry: Arc<dyn Registry>, token: Token) -> crate::Result<HttpResponse> {
+    let id = id_param(&ctx)?;
+    let version_str = ctx.require_param("version").map_err(|e| Error::BadRequest(e.to_string()))?;
+    let version = semver::Version::parse(version_str).map_err(|e| Error::InvalidVersion(e.to_string()))?;
+    require_owner(&registry, &token, id).await?;
+    let info = registry.unyank(id, &version).await?;
+    response::ok(&info).map_err(|e| Error::Internal(e.to_string()))
+}
+
+pub async fn list_owners(ctx: Context, registry: Arc<dyn Registry>) -> crate::Result<HttpResponse> {
+    let id = id_param(&ctx)?;
+    let owners = registry.get_owners(id).await?;
+    cached_json(&ctx, &owners)
+}
+
+pub async fn add_owner(ctx: Context, registry: Arc<dyn Registry>, token: Token) -> crate::Result<HttpResponse> {
+    let id = id_param(&ctx)?;
+    let owner = ctx.require_param("owner").map_err(|e| Error::BadRequest(e.to_string()))?;
+    validate_owner(owner)?;
+    require_owner(&registry, &token, id).await?;
+    let owners = registry.add_owner(id, owner).await?;
+    response::ok(&owners).map_err(|e| Error::Internal(e.to_string()))
+}
+
+pub async fn remove_owner(ctx: Context, registry: Arc<dyn Registry>, token: Token) -> crate::Result<HttpResponse> {
+    let id = id_param(&ctx)?;
+    let owner = ctx.require_param("owner").map_err(|e| Error::BadRequest(e.to_string()))?;
+    require_owner(&registry, &token, id).await?;
+    let owners = registry.remove_owner(id, owner).await?;
+    response::ok(&owners).map_err(|e| Error::Internal(e.to_string()))
+}
+
+pub async fn list_tokens(_ctx: Context, tokens: Arc<TokenStore>, _token: Token) -> crate::Result<HttpResponse> {
+    let list = tokens.list().await?;
+    response::ok(&list).map_err(|e| Error::Internal(e.to_string()))
+}
+
+pub async fn create_token(ctx: Context, tokens: Arc<TokenStore>, _token: Token) -> crate::Result<HttpResponse> {
+    let request: NewToken = serde_json::from_slice(ctx.request.body())
+        .map_err(|e| Error::BadRequest(e.to_string()))?;
+    let minted = tokens.create(request).await?;
+    response::ok(&minted).map_err(|e| Error::Internal(e.to_string()))
+}
+
+pub async fn revoke_token(ctx: Context, tokens: Arc<TokenStore>, _token: Token) -> crate::Result<HttpResponse> {
+    let id = ctx.require_param("id").map_err(|e| Error::BadRequest(e.to_string()))?;
+    tokens.revoke(id).await?;
+    response::ok(&serde_json::json!({ "revoked": id })).map_err(|e| Error::Internal(e.to_string()))
+}
+
+/// Serialize `value` as JSON with a content-hash `ETag`, answering `304 Not Modified` when the
+/// client already has the same representation.
+fn cached_json<T: serde::Serialize>(ctx: &Context, value: &T) -> crate::Result<HttpResponse> {
+    let body = serde_json::to_vec(value)?;
+    let etag = format!("\"{}\"", hex::encode(Sha256::digest(&body)));
+    if if_none_match(ctx, &etag) {
+        return not_modified(&etag, METADATA_CACHE_CONTROL);
+    }
+
+    let mut resp = response::ok(value).map_err(|e| Error::Internal(e.to_string()))?;
+    let headers = resp.headers_mut();
+    headers.insert(header::ETAG, header_value(&etag)?);
+    headers.insert(header::CACHE_CONTROL, header::HeaderValue::from_static(METADATA_CACHE_CONTROL));
+    Ok(resp)
+}
+
+/// Whether the request's `If-None-Match` header matches `etag`.
+fn if_none_match(ctx: &Context, etag: &str) -> bool {
+    let Some(value) = header_str(ctx, header::IF_NONE_MATCH) else {
+        return false;
+    };
+    value.split(',').map(str::trim).any(|candidate| {
+        candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+    })
+}
+
+fn not_modified(etag: &str, cache_control: &'static str) -> crate::Result<HttpResponse> {
+    http::Response::builder()
+        .status(http::StatusCode::NOT_MODIFIED)
+        .header(header::ETAG, etag)
+        .header(header::CACHE_CONTROL, cache_control)
+        .body(empty_body())
+        .map_err(|e| Error::Internal(e.to_string()))
+}
+
+fn header_value(value: &str) -> crate::Result<header::HeaderValue> {
+    header::HeaderValue::from_str(value).map_err(|e| Error::Internal(e.to_string()))
+}
+
+/// Byte range selected by a `Range` header, resolved against the package length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RangeRequest {
+    /// No usable range; serve the whole package.
+    Full,
+    /// Serve bytes `start..end`.
+    Partial { start: u64, end: u64 },
+    /// The range lies entirely outside the package.
+    Unsatisfiable,
+}
+
+/// Parse a single `bytes=` range. Multiple or malformed ranges are ignored, as RFC 9110 allows.
+fn parse_range(value: Option<&str>, length: u64) -> RangeRequest {
+    let Some(spec) = value.and_then(|v| v.trim().strip_prefix("bytes=")) else {
+        return RangeRequest::Full;
+    };
+    if spec.contains(',') {
+        return RangeRequest::Full;
+    }
+    let Some((first, last)) = spec.split_once('-') else {
+        return RangeRequest::Full;
+    };
+    let (first, last) = (first.trim(), last.trim());
+
+    if first.is_empty() {
+        let Ok(suffix) = last.parse::<u64>() else {
+            return RangeRequest::Full;
+        };
+        if suffix == 0 || length == 0 {
+            return RangeRequest::Unsatisfiable;
+        }
+        return RangeRequest::Partial { start: length.saturating_sub(suffix), end: length };
+    }
+
+    let Ok(start) = first.parse::<u64>() else {
+        return RangeRequest::Full;
+    };
+    let end = if last.is_empty() {
+        length
+    } else {
+        match last.parse::<u64>() {
+            Ok(last) if last >= start => last.saturating_add(1).min(length),
+            _ => return RangeRequest::Full,
+        }
+    };
+    if start >= length {
+        return RangeRequest::Unsatisfiable;
+    }
+    RangeRequest::Partial { start, end }
+}
+
+/// Whether an `If-Range` precondition (if any) still matches the package.
+fn if_range_matches(ctx: &Context, info: &Version, last_modified: &str) -> bool {
+    match header_str(ctx, header::IF_RANGE) {
+        None => true,
+        Some(value) => {
+            let value = value.trim();
+            value == format!("\"{}\"", info.checksum_sha256) || value == last_modified
         }
     }
-    map
 }
 
+/// Format a version's publish time as an HTTP date.
+fn http_date(info: &Version) -> String {
+    info.created_at.strftime("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+fn header_str(ctx: &Context, name: header::HeaderName) -> Option<&str> {
+    ctx.request.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+/// A JSON `{"error": message}` response with `status`, for errors runway has no variant for.
+pub(crate) fn error_response(status: http::StatusCode, message: &str) -> HttpResponse {
+    let body = serde_json::json!({ "error": message }).to_string();
+    let mut resp = http::Response::new(response::Body::new(
+        http_body_util::Full::new(bytes::Bytes::from(body)).map_err(|never| match never {}),
+    ));
+    *resp.status_mut() = status;
+    resp.headers_mut().insert(header::CONTENT_TYPE, header::HeaderValue::from_static("application/json"));
+    resp
+}
+
+fn empty_body() -> response::Body {
+    response::Body::new(Empty::new().map_err(|never| match never {}))
+}
+
+/// Add the package checksum as `Repr-Digest` (RFC 9530), legacy `Digest` and `X-Checksum-Sha256`,
+/// so clients can verify a download without fetching the version metadata.
+fn digest_headers(builder: http::response::Builder, info: &Version) -> crate::Result<http::response::Builder> {
+    let raw = hex::decode(&info.checksum_sha256).map_err(|e| Error::Internal(format!("Corrupt checksum: {}", e)))?;
+    let encoded = base64::engine::general_purpose::STANDARD.encode(raw);
+    Ok(builder
+        .header("repr-digest", format!("sha-256=:{}:", encoded))
+        .header("digest", format!("SHA-256={}", encoded))
+        .header("x-checksum-sha256", &info.checksum_sha256))
+}
+
+/// Stream a package body to the client with a known `Content-Length`.
+fn package_response(builder: http::response::Builder, package: PackageStream, filename: &str) -> crate::Result<HttpResponse> {
+    let body = StreamBody::new(ReaderStream::new(package.reader).map_ok(Frame::data));
+    builder
+        .header(header::CONTENT_TYPE, PACKAGE_CONTENT_TYPE)
+        .header(header::CONTENT_LENGTH, package.length)
+        .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename))
+        .body(response::Body::new(body))
+        .map_err(|e| Error::Internal(e.to_string()))
+}
+
+/// Ensure `token` may manage extension `id`: admins always can, others must be listed as an owner.
+async fn require_owner(registry: &Arc<dyn Registry>, token: &Token, id: &str) -> crate::Result<()> {
+    if token.has_scope(Scope::Admin) {
+        return Ok(());
+    }
+    if !token.allows(id) {
+        return Err(Error::Forbidden(format!("Token {} may not manage {}", token.id, id)));
+    }
+    let owners = registry.get_owners(id).await?;
+    if !owners.iter().any(|o| o == &token.owner) {
+        return Err(Error::Forbidden(format!("{} is not an owner of {}", token.owner, id)));
+    }
+    Ok(())
+}
+
+/// Extract and validate the `{id}` route parameter.
+fn id_param(ctx: &Context) -> crate::Result<&str> {
+    let id = ctx.require_param("id").map_err(|e| Error::BadRequest(e.to_string()))?;
+    validate_id(id)?;
+    Ok(id)
+}
+
+/// Parse the optional `?host_version=` parameter.
+fn host_version_param(query_params: &std::collections::HashMap<String, String>) -> crate::Result<Option<semver::Version>> {
+    query_params
+        .get("host_version")
+        .map(|v| semver::Version::parse(v).map_err(|e| Error::InvalidVersion(e.to_string())))
+        .transpose()
+}
+
+/// Parse repeatable metadata filters such as `?capability=a,b&capability=c`. Repeated parameters must
+/// all match; comma-separated values within one parameter are alternatives.
+fn parse_filters(query: Option<&str>) -> Vec<Filter> {
+    query_pairs(query)
+        .filter_map(|(key, value)| {
+            let field = FilterField::from_param(&key)?;
+            let values: Vec<String> = value.split(',').map(str::trim).filter(|v| !v.is_empty()).map(String::from).collect();
+            (!values.is_empty()).then_some(Filter { field, values })
+        })
+        .collect()
+}
+
+/// Parse `?facets=category,license`.
+fn parse_facets(facets: Option<&String>) -> crate::Result<Vec<FilterField>> {
+    let Some(facets) = facets else {
+        return Ok(Vec::new());
+    };
+    let mut fields = Vec::new();
+    for name in facets.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+        let field = FilterField::from_param(name).ok_or_else(|| {
+            Error::BadRequest(format!(
+                "Unknown facet {:?}: expected category, keyword, capability, operation, author or license",
+                name
+            ))
+        })?;
+        if !fields.contains(&field) {
+            fields.push(field);
+        }
+    }
+    Ok(fields)
+}
+
+/// Decoded `key=value` pairs of a query string, in order and including repeated keys.
+fn query_pairs(query: Option<&str>) -> impl Iterator<Item = (String, String)> + '_ {
+    query
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|part| part.split_once('='))
+        .map(|(key, value)| (urldecode(key), urldecode(value)))
+}
+
+/// Query parameters by name. Of repeated parameters, the last one wins.
+fn parse_query(query: Option<&str>) -> std::collections::HashMap<String, String> {
+    query_pairs(query).collect()
+}
+
+/// Decode a query string component. Percent-encoded bytes are UTF-8; invalid sequences become U+FFFD.
 fn urldecode(s: &str) -> String {
-    let mut result = String::new();
-    let mut chars = s.chars().peekable();
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut chars = s.chars();
     while let Some(c) = chars.next() {
         match c {
             '%' => {
                 let hex: String = chars.by_ref().take(2).collect();
                 if let Ok(byte) = u8::from_str_radix(&hex, 16) {
-                    result.push(byte as char);
+                    bytes.push(byte);
                 }
             }
-            '+' => result.push(' '),
-            _ => result.push(c),
+            '+' => bytes.push(b' '),
+            _ => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
         }
     }
-    result
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(value: &str, length: u64) -> RangeRequest {
+        parse_range(Some(value), length)
+    }
+
+    #[test]
+    fn without_range_serves_everything() {
+        assert_eq!(parse_range(None, 100), RangeRequest::Full);
+        assert_eq!(range("items=0-9", 100), RangeRequest::Full);
+        assert_eq!(range("bytes=abc", 100), RangeRequest::Full);
+    }
+
+    #[test]
+    fn closed_range() {
+        assert_eq!(range("bytes=0-9", 100), RangeRequest::Partial { start: 0, end: 10 });
+        assert_eq!(range(" bytes=10-10 ", 100), RangeRequest::Partial { start: 10, end: 11 });
+    }
+
+    #[test]
+    fn open_ended_range() {
+        assert_eq!(range("bytes=90-", 100), RangeRequest::Partial { start: 90, end: 100 });
+        assert_eq!(range("bytes=99-", 100), RangeRequest::Partial { start: 99, end: 100 });
+    }
+
+    #[test]
+    fn suffix_range() {
+        assert_eq!(range("bytes=-10", 100), RangeRequest::Partial { start: 90, end: 100 });
+        // A suffix longer than the package selects all of it.
+        assert_eq!(range("bytes=-500", 100), RangeRequest::Partial { start: 0, end: 100 });
+        assert_eq!(range("bytes=-0", 100), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn last_beyond_end_is_clamped() {
+        assert_eq!(range("bytes=50-1000", 100), RangeRequest::Partial { start: 50, end: 100 });
+        assert_eq!(range("bytes=0-18446744073709551615", 100), RangeRequest::Partial { start: 0, end: 100 });
+    }
+
+    #[test]
+    fn start_past_end_is_unsatisfiable() {
+        assert_eq!(range("bytes=100-", 100), RangeRequest::Unsatisfiable);
+        assert_eq!(range("bytes=100-200", 100), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn multiple_and_reversed_ranges_are_ignored() {
+        assert_eq!(range("bytes=0-9,20-29", 100), RangeRequest::Full);
+        assert_eq!(range("bytes=9-0", 100), RangeRequest::Full);
+    }
+
+    #[test]
+    fn decodes_utf8_query() {
+        assert_eq!(urldecode("caf%C3%A9"), "café");
+        assert_eq!(urldecode("caf%c3%a9+au+lait"), "café au lait");
+        assert_eq!(urldecode("café"), "café");
+        assert_eq!(urldecode("%FF"), "\u{FFFD}");
+        assert_eq!(urldecode("100%25"), "100%");
+    }
+
+    #[test]
+    fn empty_package() {
+        assert_eq!(range("bytes=0-", 0), RangeRequest::Unsatisfiable);
+        assert_eq!(range("bytes=0-9", 0), RangeRequest::Unsatisfiable);
+        assert_eq!(range("bytes=-10", 0), RangeRequest::Unsatisfiable);
+    }
 }
@@ -14,8 +14,11 @@
 //! ext.routes(&mut router);
 //! ```
 
+pub mod auth;
 pub mod handler;
+pub mod package;
 pub mod registry;
+pub mod resolve;
 pub mod types;
 
 use std::path::PathBuf;
@@ -23,9 +26,15 @@ use std::sync::Arc;
 
 use runway::{Module, Router};
 
+pub use auth::{Scope, Token, TokenStore};
 pub use registry::fs::FilesystemRegistry;
-pub use registry::Registry;
-pub use types::{Details, ListOptions, Page, Summary, Version};
+#[cfg(feature = "sqlite")]
+pub use registry::sqlite::SqliteRegistry;
+pub use registry::{BlobStore, CompositeRegistry, Index, Registry};
+pub use types::{
+    Category, CategoryCount, Details, Filter, FilterField, ListOptions, Page, PublishOptions, ResolveRequest, Resolved, SortField, SortOrder,
+    Suggestion, Summary, Version,
+};
 
 /// Extension-specific errors.
 #[derive(Debug, thiserror::Error)]
@@ -36,6 +45,9 @@ pub enum Error {
     #[error("Version not found: {id}@{version}")]
     VersionNotFound { id: String, version: String },
 
+    #[error("Version already exists: {id}@{version}")]
+    VersionExists { id: String, version: String },
+
     #[error("Invalid version: {0}")]
     InvalidVersion(String),
 
@@ -45,12 +57,28 @@ pub enum Error {
     #[error("Bad request: {0}")]
     BadRequest(String),
 
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+
+    #[error("Cannot resolve dependencies: {0}")]
+    Unresolvable(String),
+
+    #[error("Package too large: {size} bytes exceeds limit of {limit} bytes")]
+    TooLarge { size: u64, limit: u64 },
+
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 
     #[error("JSON error: {0}")]
     Json(#[from] serde_json::Error),
 
+    #[cfg(feature = "sqlite")]
+    #[error("Database error: {0}")]
+    Database(#[from] rusqlite::Error),
+
     #[error("Internal error: {0}")]
     Internal(String),
 }
@@ -62,11 +90,21 @@ impl From<Error> for runway::Error {
             Error::VersionNotFound { id, version } => {
                 runway::Error::NotFound(format!("{}@{}", id, version))
             }
+            Error::VersionExists { id, version } => {
+                runway::Error::Conflict(format!("{}@{} already exists", id, version))
+            }
             Error::InvalidVersion(msg) | Error::InvalidPackage(msg) | Error::BadRequest(msg) => {
                 runway::Error::BadRequest(msg)
             }
+            // Handlers answer these with 413 directly, see `respond`.
+            e @ Error::TooLarge { .. } => runway::Error::BadRequest(e.to_string()),
+            e @ Error::Unresolvable(_) => runway::Error::Conflict(e.to_string()),
+            Error::Unauthorized(msg) => runway::Error::Unauthorized(msg),
+            Error::Forbidden(msg) => runway::Error::Forbidden(msg),
             Error::Io(e) => runway::Error::Internal(e.to_string()),
             Error::Json(e) => runway::Error::Internal(e.to_string()),
+            #[cfg(feature = "sqlite")]
+            Error::Database(e) => runway::Error::Internal(e.to_string()),
             Error::Internal(msg) => runway::Error::Internal(msg),
         }
     }
@@ -74,22 +112,59 @@ impl From<Error> for runway::Error {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Default upper bound for uploaded package bodies (64 MiB).
+pub const DEFAULT_MAX_PACKAGE_SIZE: usize = 64 * 1024 * 1024;
+
 /// Extension registry module.
 pub struct ExtensionModule {
     registry: Arc<dyn Registry>,
+    tokens: Option<Arc<TokenStore>>,
+    max_package_size: usize,
+    categories: Arc<Vec<Category>>,
 }
 
 impl ExtensionModule {
     /// Create a new extension module with a filesystem registry.
+    ///
+    /// API tokens are kept in `tokens.json` inside the registry directory.
     pub fn new(registry_path: PathBuf) -> Self {
-        Self {
-            registry: Arc::new(FilesystemRegistry::new(registry_path)),
-        }
+        let tokens = Arc::new(TokenStore::new(registry_path.join("tokens.json")));
+        Self::with_registry(Arc::new(FilesystemRegistry::new(registry_path))).with_tokens(tokens)
     }
 
     /// Create a new extension module with a custom registry implementation.
+    ///
+    /// Write endpoints refuse every request until API tokens are configured with [`Self::with_tokens`].
     pub fn with_registry(registry: Arc<dyn Registry>) -> Self {
-        Self { registry }
+        Self {
+            registry,
+            tokens: None,
+            max_package_size: DEFAULT_MAX_PACKAGE_SIZE,
+            categories: Arc::new(Vec::new()),
+        }
+    }
+
+    /// Authenticate write endpoints against `tokens`, and serve the token management endpoints.
+    pub fn with_tokens(mut self, tokens: Arc<TokenStore>) -> Self {
+        self.tokens = Some(tokens);
+        self
+    }
+
+    /// Set the maximum accepted size of a published package, in bytes.
+    ///
+    /// Uploads declaring a larger `Content-Length` are refused with `413 Payload Too Large` before the
+    /// package is parsed. runway buffers request bodies before handlers run, so servers exposed to
+    /// untrusted clients should also cap the body size in front of it.
+    pub fn with_max_package_size(mut self, bytes: usize) -> Self {
+        self.max_package_size = bytes;
+        self
+    }
+
+    /// Restrict published packages to these categories, and describe them in `/api/v1/categories`.
+    /// Any category is accepted if the list is empty, which is the default.
+    pub fn with_categories(mut self, categories: Vec<Category>) -> Self {
+        self.categories = Arc::new(categories);
+        self
     }
 }
 
@@ -101,29 +176,104 @@ impl Module for ExtensionModule {
     fn routes(&self, router: &mut Router) {
         use handler::*;
         let r = &self.registry;
+        let t = &self.tokens;
+        let max_size = self.max_package_size;
+        let categories = &self.categories;
 
         router.get("/api/v1/extensions", with(r, list_extensions));
+        router.get("/api/v1/extensions/suggest", with(r, suggest));
         router.get("/api/v1/extensions/{id}", with(r, get_extension));
         router.get("/api/v1/extensions/{id}/versions", with(r, list_versions));
         router.get("/api/v1/extensions/{id}/versions/{version}", with(r, get_version));
         router.get("/api/v1/extensions/{id}/versions/{version}/download", with(r, download));
+        router.get("/api/v1/extensions/{id}/latest", with(r, get_latest_version));
         router.get("/api/v1/extensions/{id}/latest/download", with(r, download_latest));
+        router.put(
+            "/api/v1/extensions",
+            guarded(t, Scope::Publish, r, {
+                let categories = categories.clone();
+                move |ctx, r, token| publish(ctx, r, token, max_size, categories.clone())
+            }),
+        );
+        router.put("/api/v1/extensions/{id}/versions/{version}/yank", guarded(t, Scope::Yank, r, yank));
+        router.delete("/api/v1/extensions/{id}/versions/{version}/yank", guarded(t, Scope::Yank, r, unyank));
+
+        router.post("/api/v1/resolve", with(r, resolve_dependencies));
+
+        router.get(
+            "/api/v1/categories",
+            with(r, {
+                let categories = categories.clone();
+                move |ctx, r| list_categories(ctx, r, categories.clone())
+            }),
+        );
+
+        router.get("/api/v1/extensions/{id}/owners", with(r, list_owners));
+        router.put("/api/v1/extensions/{id}/owners/{owner}", guarded(t, Scope::Publish, r, add_owner));
+        router.delete("/api/v1/extensions/{id}/owners/{owner}", guarded(t, Scope::Publish, r, remove_owner));
+
+        if let Some(tokens) = t {
+            router.get("/api/v1/tokens", guarded(t, Scope::Admin, tokens, list_tokens));
+            router.post("/api/v1/tokens", guarded(t, Scope::Admin, tokens, create_token));
+            router.delete("/api/v1/tokens/{id}", guarded(t, Scope::Admin, tokens, revoke_token));
+        }
+    }
+}
+
+type BoxedResponse = std::pin::Pin<Box<dyn std::future::Future<Output = runway::Result<runway::response::HttpResponse>> + Send>>;
+
+/// Helper to bind shared state (usually the registry) to a handler function.
+fn with<S, F, Fut>(
+    state: &S,
+    handler: F,
+) -> impl Fn(runway::Context) -> BoxedResponse + Send + Sync + 'static
+where
+    S: Clone + Send + Sync + 'static,
+    F: Fn(runway::Context, S) -> Fut + Send + Sync + Clone + 'static,
+    Fut: std::future::Future<Output = Result<runway::response::HttpResponse>> + Send + 'static,
+{
+    let state = state.clone();
+    move |ctx| {
+        let state = state.clone();
+        let handler = handler.clone();
+        Box::pin(async move { respond(handler(ctx, state).await) })
     }
 }
 
-/// Helper to bind a registry to a handler function.
-fn with<F, Fut>(
-    registry: &Arc<dyn Registry>,
+/// Like [`with`], but only runs the handler for requests carrying a token with `scope`. Without a
+/// token store every request is refused.
+fn guarded<S, F, Fut>(
+    tokens: &Option<Arc<TokenStore>>,
+    scope: Scope,
+    state: &S,
     handler: F,
-) -> impl Fn(runway::Context) -> std::pin::Pin<Box<dyn std::future::Future<Output = runway::Result<runway::response::HttpResponse>> + Send>> + Send + Sync + 'static
+) -> impl Fn(runway::Context) -> BoxedResponse + Send + Sync + 'static
 where
-    F: Fn(runway::Context, Arc<dyn Registry>) -> Fut + Send + Sync + Clone + 'static,
+    S: Clone + Send + Sync + 'static,
+    F: Fn(runway::Context, S, Token) -> Fut + Send + Sync + Clone + 'static,
     Fut: std::future::Future<Output = Result<runway::response::HttpResponse>> + Send + 'static,
 {
-    let r = registry.clone();
+    let tokens = tokens.clone();
+    let state = state.clone();
     move |ctx| {
-        let r = r.clone();
+        let tokens = tokens.clone();
+        let state = state.clone();
         let handler = handler.clone();
-        Box::pin(async move { handler(ctx, r).await.map_err(Into::into) })
+        Box::pin(async move {
+            let Some(tokens) = tokens else {
+                return Err(Error::Unauthorized("API tokens are not configured".into()).into());
+            };
+            let token = tokens.authorize(&ctx, scope).await?;
+            respond(handler(ctx, state, token).await)
+        })
+    }
+}
+
+/// Convert a handler result for runway. runway has no error for `413 Payload Too Large`, so oversized
+/// uploads get their response here.
+fn respond(result: Result<runway::response::HttpResponse>) -> runway::Result<runway::response::HttpResponse> {
+    match result {
+        Err(e @ Error::TooLarge { .. }) => Ok(handler::error_response(http::StatusCode::PAYLOAD_TOO_LARGE, &e.to_string())),
+        result => result.map_err(Into::into),
     }
 }
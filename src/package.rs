@@ -0,0 +1,177 @@
+//! Extension package parsing.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::registry::validate_id;
+use crate::{Error, Result};
+
+/// Largest `manifest.json` read from a package. Packages are gzipped, so a small upload could
+/// otherwise inflate into an arbitrarily large manifest.
+const MAX_MANIFEST_SIZE: u64 = 1024 * 1024;
+
+/// Metadata declared by a package's `manifest.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub id: String,
+    pub version: semver::Version,
+    pub name: String,
+    pub description: String,
+    pub author: String,
+    pub license: String,
+    #[serde(default)]
+    pub categories: Vec<String>,
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    #[serde(default)]
+    pub homepage: Option<String>,
+    #[serde(default)]
+    pub repository: Option<String>,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    #[serde(default)]
+    pub config_schema: Option<serde_json::Value>,
+    #[serde(default)]
+    pub operations: Vec<String>,
+    /// Other extensions this one needs, by id.
+    #[serde(default)]
+    pub dependencies: BTreeMap<String, semver::VersionReq>,
+    /// Host versions the extension runs on.
+    #[serde(default)]
+    pub host: Option<semver::VersionReq>,
+}
+
+impl Manifest {
+    /// Read the manifest from a gzipped tarball.
+    pub fn from_package(package: &[u8]) -> Result<Self> {
+        let decoder = flate2::read::GzDecoder::new(package);
+        let mut archive = tar::Archive::new(decoder);
+
+        for entry in archive.entries().map_err(|e| Error::InvalidPackage(e.to_string()))? {
+            let mut entry = entry.map_err(|e| Error::InvalidPackage(e.to_string()))?;
+            let path = entry.path().map_err(|e| Error::InvalidPackage(e.to_string()))?;
+            if path.ends_with("manifest.json") {
+                let mut content = String::new();
+                std::io::Read::read_to_string(&mut std::io::Read::take(&mut entry, MAX_MANIFEST_SIZE + 1), &mut content)
+                    .map_err(|e| Error::InvalidPackage(e.to_string()))?;
+                if content.len() as u64 > MAX_MANIFEST_SIZE {
+                    return Err(Error::InvalidPackage(format!("manifest.json exceeds {} bytes", MAX_MANIFEST_SIZE)));
+                }
+                let manifest = serde_json::from_str(&content)
+                    .map_err(|e| Error::InvalidPackage(format!("Invalid manifest.json: {}", e)))?;
+                return Self::from_json(&manifest);
+            }
+        }
+
+        Err(Error::InvalidPackage("Missing manifest.json".into()))
+    }
+
+    /// Build a manifest from raw `manifest.json` contents, filling in defaults.
+    pub fn from_json(manifest: &serde_json::Value) -> Result<Self> {
+        let id = manifest["id"].as_str().ok_or_else(|| Error::InvalidPackage("Missing id".into()))?;
+        validate_id(id)?;
+        let version_str = manifest["version"].as_str().ok_or_else(|| Error::InvalidPackage("Missing version".into()))?;
+        let version = semver::Version::parse(version_str).map_err(|e| Error::InvalidVersion(e.to_string()))?;
+
+        Ok(Self {
+            id: id.to_string(),
+            version,
+            name: manifest["name"].as_str().unwrap_or(id).to_string(),
+            description: manifest["description"].as_str().unwrap_or("").to_string(),
+            author: manifest["author"].as_str().unwrap_or("").to_string(),
+            license: manifest["license"].as_str().unwrap_or("MIT").to_string(),
+            categories: extract_strings(&manifest["categories"]),
+            keywords: extract_strings(&manifest["keywords"]),
+            homepage: manifest["homepage"].as_str().map(String::from),
+            repository: manifest["repository"].as_str().map(String::from),
+            capabilities: extract_strings(&manifest["capabilities"]),
+            config_schema: manifest.get("config_schema").cloned(),
+            operations: extract_strings(&manifest["operations"]),
+            dependencies: parse_dependencies(id, &manifest["dependencies"])?,
+            host: parse_host(manifest)?,
+        })
+    }
+}
+
+/// Parse a `{"id": "^1.2"}` map of dependencies.
+fn parse_dependencies(id: &str, value: &serde_json::Value) -> Result<BTreeMap<String, semver::VersionReq>> {
+    let Some(map) = value.as_object() else {
+        return match value {
+            serde_json::Value::Null => Ok(BTreeMap::new()),
+            _ => Err(Error::InvalidPackage("dependencies must be an object of id to version requirement".into())),
+        };
+    };
+
+    let mut dependencies = BTreeMap::new();
+    for (dep, req) in map {
+        validate_id(dep)?;
+        if dep == id {
+            return Err(Error::InvalidPackage(format!("{} cannot depend on itself", id)));
+        }
+        let req = req
+            .as_str()
+            .ok_or_else(|| Error::InvalidPackage(format!("Requirement for dependency {} must be a string", dep)))?;
+        let req = semver::VersionReq::parse(req)
+            .map_err(|e| Error::InvalidPackage(format!("Invalid requirement {:?} for dependency {}: {}", req, dep, e)))?;
+        dependencies.insert(dep.clone(), req);
+    }
+    Ok(dependencies)
+}
+
+/// Parse the host requirement, given as `"host": "^0.5"` or `"engines": {"host": "^0.5"}`.
+fn parse_host(manifest: &serde_json::Value) -> Result<Option<semver::VersionReq>> {
+    let value = match &manifest["host"] {
+        serde_json::Value::Null => &manifest["engines"]["host"],
+        value => value,
+    };
+    if value.is_null() {
+        return Ok(None);
+    }
+    let req = value
+        .as_str()
+        .ok_or_else(|| Error::InvalidPackage("host must be a version requirement string".into()))?;
+    semver::VersionReq::parse(req)
+        .map(Some)
+        .map_err(|e| Error::InvalidPackage(format!("Invalid host requirement {:?}: {}", req, e)))
+}
+
+fn extract_strings(value: &serde_json::Value) -> Vec<String> {
+    value.as_array()
+        .map(|a| a.iter().filter_map(|v| v.as_str().map(String::from)).collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package(manifest: &[u8]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default()));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(manifest.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, "manifest.json", manifest).unwrap();
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn reads_manifest() {
+        let manifest = Manifest::from_package(&package(br#"{"id": "hello", "version": "1.0.0"}"#)).unwrap();
+        assert_eq!(manifest.id, "hello");
+        assert_eq!(manifest.name, "hello");
+    }
+
+    #[test]
+    fn malformed_manifest_is_invalid_package() {
+        let err = Manifest::from_package(&package(b"{\"id\": ")).unwrap_err();
+        assert!(matches!(err, Error::InvalidPackage(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn missing_manifest_is_invalid_package() {
+        let err = Manifest::from_package(b"not a tarball").unwrap_err();
+        assert!(matches!(err, Error::InvalidPackage(_)), "{:?}", err);
+    }
+}
@@ -1,13 +1,77 @@
 //! Extension registry abstraction.
 
+use std::path::{Path, PathBuf};
+
 use async_trait::async_trait;
 use bytes::Bytes;
 use serde::{Deserialize, Serialize};
+use tokio::io::AsyncRead;
 
-use crate::types::{Details, ListOptions, Page, Summary, Version};
-use crate::Result;
+use crate::package::Manifest;
+use crate::types::{Details, ListOptions, Page, PublishOptions, Summary, Version};
+use crate::{Error, Result};
 
+pub mod blob;
+pub mod composite;
+pub mod downloads;
 pub mod fs;
+#[cfg(feature = "s3")]
+pub mod s3;
+pub mod search;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+pub use blob::BlobStore;
+pub use composite::CompositeRegistry;
+
+/// Open `path` and take an exclusive advisory lock on it, waiting for other holders. The lock is
+/// released when the returned file is dropped.
+pub(crate) async fn lock_file(path: PathBuf) -> Result<std::fs::File> {
+    tokio::task::spawn_blocking(move || lock_file_blocking(&path))
+        .await
+        .map_err(|e| Error::Internal(e.to_string()))?
+}
+
+/// Blocking version of [`lock_file`].
+pub(crate) fn lock_file_blocking(path: &Path) -> Result<std::fs::File> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let file = std::fs::OpenOptions::new().create(true).truncate(false).write(true).open(path)?;
+    file.lock()?;
+    Ok(file)
+}
+
+/// Maximum length of an extension id.
+pub const MAX_ID_LEN: usize = 64;
+
+/// Ids that would collide with fixed routes under `/api/v1/extensions/`. New extensions may not use
+/// them; extensions published before an id was reserved keep working, except for the shadowed route.
+pub const RESERVED_IDS: &[&str] = &["suggest"];
+
+/// Check that `id` is a valid extension id.
+///
+/// Ids are lowercase slugs: ASCII letters, digits, `-` and `_`, starting with a letter or digit and at
+/// most [`MAX_ID_LEN`] characters long. Ids are used as directory names, so anything else is rejected.
+pub fn validate_id(id: &str) -> Result<()> {
+    validate_slug("extension id", id, MAX_ID_LEN)
+}
+
+/// Check that `value` is a lowercase slug of at most `max_len` characters. `what` names it in the error.
+pub(crate) fn validate_slug(what: &str, value: &str, max_len: usize) -> Result<()> {
+    let valid = !value.is_empty()
+        && value.len() <= max_len
+        && value.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && value.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::BadRequest(format!(
+            "Invalid {} {:?}: expected 1-{} characters of a-z, 0-9, '-' or '_', starting with a letter or digit",
+            what, value, max_len
+        )))
+    }
+}
 
 /// Extension metadata stored in the registry.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -31,9 +95,31 @@ pub struct Meta {
     pub config_schema: Option<serde_json::Value>,
     #[serde(default)]
     pub operations: Vec<String>,
+    /// Publishers allowed to release new versions and manage owners.
+    #[serde(default)]
+    pub owners: Vec<String>,
 }
 
 impl Meta {
+    pub fn from_manifest(manifest: &Manifest, owners: Vec<String>) -> Self {
+        Self {
+            id: manifest.id.clone(),
+            name: manifest.name.clone(),
+            description: manifest.description.clone(),
+            author: manifest.author.clone(),
+            license: manifest.license.clone(),
+            categories: manifest.categories.clone(),
+            keywords: manifest.keywords.clone(),
+            homepage: manifest.homepage.clone(),
+            repository: manifest.repository.clone(),
+            capabilities: manifest.capabilities.clone(),
+            config_schema: manifest.config_schema.clone(),
+            operations: manifest.operations.clone(),
+            owners,
+        }
+    }
+
+    /// Summarize the extension at `version`, usually its latest unyanked release.
     pub fn to_summary(&self, version: &Version) -> Summary {
         Summary {
             id: self.id.clone(),
@@ -44,6 +130,8 @@ impl Meta {
             license: self.license.clone(),
             categories: self.categories.clone(),
             updated_at: version.created_at,
+            downloads: 0,
+            score: None,
         }
     }
 
@@ -68,6 +156,89 @@ impl Meta {
     }
 }
 
+/// Pick the manifest that should describe an extension: the newest stable unyanked release, falling
+/// back to the newest unyanked prerelease, then to the newest version of any kind.
+///
+/// `versions` must be ordered newest first.
+pub(crate) fn representative_manifest(versions: impl IntoIterator<Item = (Version, Manifest)>) -> Option<Manifest> {
+    let mut unyanked = None;
+    let mut newest = None;
+    for (version, manifest) in versions {
+        if !version.yanked && version.version.pre.is_empty() {
+            return Some(manifest);
+        }
+        if !version.yanked && unyanked.is_none() {
+            unyanked = Some(manifest.clone());
+        }
+        if newest.is_none() {
+            newest = Some(manifest);
+        }
+    }
+    unyanked.or(newest)
+}
+
+/// Newest unyanked version in `versions` (ordered newest first) that supports `host_version`, if given.
+pub(crate) fn latest_compatible<'a>(versions: &'a [Version], host_version: Option<&semver::Version>) -> Option<&'a Version> {
+    versions
+        .iter()
+        .find(|v| !v.yanked && host_version.is_none_or(|host| v.supports_host(host)))
+}
+
+/// Error for an extension without a version that can be served as latest.
+pub(crate) fn no_latest_version(id: &str, host_version: Option<&semver::Version>) -> Error {
+    match host_version {
+        Some(host) => Error::NotFound(format!("Extension {} has no version supporting host {}", id, host)),
+        None => Error::NotFound(format!("Extension {}", id)),
+    }
+}
+
+/// A package body that can be read incrementally.
+pub struct PackageStream {
+    pub reader: Box<dyn AsyncRead + Send + Sync + Unpin>,
+    pub length: u64,
+}
+
+/// Metadata half of a registry: extensions, versions and owners, but no package bytes.
+///
+/// Combined with a [`BlobStore`] by [`CompositeRegistry`]. Versions reference their package by
+/// [`Version::checksum_sha256`].
+#[async_trait]
+pub trait Index: Send + Sync {
+    async fn list(&self, options: ListOptions) -> Result<Page<Summary>>;
+    async fn get(&self, id: &str) -> Result<Details>;
+    async fn get_versions(&self, id: &str) -> Result<Vec<Version>>;
+    async fn get_version(&self, id: &str, version: &semver::Version) -> Result<Version>;
+    /// Latest version that has not been yanked and, if `host_version` is given, supports it.
+    async fn get_latest_version(&self, id: &str, host_version: Option<&semver::Version>) -> Result<Version>;
+
+    /// Check that `options.publisher` may publish `manifest`, before its package is stored.
+    async fn check_publish(&self, manifest: &Manifest, options: &PublishOptions) -> Result<()>;
+
+    /// Record a published version whose package is already in the blob store. Repeats the checks of
+    /// [`Index::check_publish`] atomically with the write.
+    async fn insert_version(&self, manifest: &Manifest, version: &Version, options: &PublishOptions) -> Result<()>;
+
+    async fn set_yanked(&self, id: &str, version: &semver::Version, yanked: bool, reason: Option<String>) -> Result<Version>;
+    async fn get_owners(&self, id: &str) -> Result<Vec<String>>;
+    async fn add_owner(&self, id: &str, owner: &str) -> Result<Vec<String>>;
+    async fn remove_owner(&self, id: &str, owner: &str) -> Result<Vec<String>>;
+
+    /// Lock out other processes sharing this index from storing or releasing blobs until the returned
+    /// file is dropped. Indexes that cannot be shared between processes need not lock.
+    async fn lock_blobs(&self) -> Result<Option<std::fs::File>> {
+        Ok(None)
+    }
+
+    /// Every version of every extension with its extension id, for maintenance such as verifying
+    /// packages or finding blobs that are no longer referenced.
+    async fn all_versions(&self) -> Result<Vec<(String, Version)>>;
+
+    /// Count a download of `id@version`. Indexes without download counts ignore it.
+    async fn record_download(&self, _id: &str, _version: &semver::Version) -> Result<()> {
+        Ok(())
+    }
+}
+
 /// Registry trait for extension storage backends.
 #[async_trait]
 pub trait Registry: Send + Sync {
@@ -76,6 +247,89 @@ pub trait Registry: Send + Sync {
     async fn get_versions(&self, id: &str) -> Result<Vec<Version>>;
     async fn get_version(&self, id: &str, version: &semver::Version) -> Result<Version>;
     async fn download(&self, id: &str, version: &semver::Version) -> Result<Bytes>;
-    async fn publish(&self, package: Bytes) -> Result<()>;
-    async fn get_latest_version(&self, id: &str) -> Result<Version>;
+
+    /// Open a package for streaming instead of buffering it in memory.
+    ///
+    /// The default implementation falls back to [`Registry::download`]; backends that can read
+    /// incrementally should override it.
+    async fn download_stream(&self, id: &str, version: &semver::Version) -> Result<PackageStream> {
+        let data = self.download(id, version).await?;
+        Ok(PackageStream {
+            length: data.len() as u64,
+            reader: Box::new(std::io::Cursor::new(data)),
+        })
+    }
+
+    /// Open the bytes `start..end` of a package for streaming, for HTTP range requests.
+    ///
+    /// Callers must keep the range within the package's `size_bytes`.
+    async fn download_range(&self, id: &str, version: &semver::Version, start: u64, end: u64) -> Result<PackageStream> {
+        let data = self.download(id, version).await?;
+        let end = (end as usize).min(data.len());
+        let start = (start as usize).min(end);
+        Ok(PackageStream {
+            length: (end - start) as u64,
+            reader: Box::new(std::io::Cursor::new(data.slice(start..end))),
+        })
+    }
+
+    /// A URL the package can be fetched from directly, when the backend serves packages from
+    /// elsewhere (e.g. presigned object storage URLs). Handlers redirect to it instead of streaming.
+    async fn download_url(&self, _id: &str, _version: &semver::Version) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// Publish a package. Fails with [`Error::VersionExists`] if the version is already published,
+    /// unless `options.overwrite` is set.
+    async fn publish(&self, package: Bytes, options: &PublishOptions) -> Result<Version>;
+    /// Latest version that has not been yanked and, if `host_version` is given, supports it.
+    async fn get_latest_version(&self, id: &str, host_version: Option<&semver::Version>) -> Result<Version>;
+    async fn yank(&self, id: &str, version: &semver::Version, reason: Option<String>) -> Result<Version>;
+    async fn unyank(&self, id: &str, version: &semver::Version) -> Result<Version>;
+    async fn get_owners(&self, id: &str) -> Result<Vec<String>>;
+    async fn add_owner(&self, id: &str, owner: &str) -> Result<Vec<String>>;
+    async fn remove_owner(&self, id: &str, owner: &str) -> Result<Vec<String>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejected(id: &str) -> bool {
+        matches!(validate_id(id), Err(Error::BadRequest(_)))
+    }
+
+    #[test]
+    fn accepts_slugs() {
+        for id in ["hello", "hello-world", "hello_world", "0day", "a", "v2"] {
+            assert!(validate_id(id).is_ok(), "{}", id);
+        }
+    }
+
+    #[test]
+    fn rejects_paths() {
+        for id in ["../../etc", "..", ".", "/abs", "a/b", "a\\b", ".hidden", "a\0b"] {
+            assert!(rejected(id), "{:?}", id);
+        }
+    }
+
+    #[test]
+    fn rejects_other_characters() {
+        for id in ["", "Hello", "HELLO", "-leading", "_leading", "has space", "dot.ted", "café"] {
+            assert!(rejected(id), "{:?}", id);
+        }
+    }
+
+    #[test]
+    fn limits_length() {
+        assert!(validate_id(&"a".repeat(MAX_ID_LEN)).is_ok());
+        assert!(rejected(&"a".repeat(MAX_ID_LEN + 1)));
+        assert_eq!(MAX_ID_LEN, 64);
+    }
+
+    #[test]
+    fn names_the_value_in_errors() {
+        let err = validate_slug("owner", "Team", 64).unwrap_err();
+        assert!(err.to_string().contains("Invalid owner \"Team\""), "{}", err);
+    }
 }
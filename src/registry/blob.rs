@@ -0,0 +1,192 @@
+//! Content-addressed storage for package bytes.
+
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tracing::{debug, info};
+
+use crate::registry::PackageStream;
+use crate::{Error, Result};
+
+/// Check that `hash` is a lowercase hex SHA-256 digest. Hashes end up in paths and object keys.
+pub fn validate_hash(hash: &str) -> Result<()> {
+    if hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        Ok(())
+    } else {
+        Err(Error::BadRequest(format!("Invalid SHA-256 digest {:?}", hash)))
+    }
+}
+
+/// Key of a blob relative to the store root: `blobs/sha256/ab/cd/abcd...`.
+///
+/// The two levels of two-character prefixes keep directories small on filesystems.
+pub fn blob_key(hash: &str) -> Result<String> {
+    validate_hash(hash)?;
+    Ok(format!("blobs/sha256/{}/{}/{}", &hash[..2], &hash[2..4], hash))
+}
+
+/// Check that `data` hashes to `hash`.
+pub fn verify_digest(hash: &str, data: &[u8]) -> Result<()> {
+    let actual = hex::encode(Sha256::digest(data));
+    if actual == hash {
+        Ok(())
+    } else {
+        Err(Error::Internal(format!("Blob {} has checksum {}", hash, actual)))
+    }
+}
+
+fn blob_not_found(hash: &str) -> Error {
+    Error::NotFound(format!("Blob {}", hash))
+}
+
+/// Storage for package archives, addressed by the hex SHA-256 of their contents.
+///
+/// Metadata lives in an [`Index`](crate::registry::Index); a blob store only holds bytes. Identical
+/// packages share one blob, whichever extensions and versions they belong to. Missing blobs are
+/// reported as [`Error::NotFound`].
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    /// Store `data` under `hash`, which must be its SHA-256. Blobs that are already stored are not
+    /// written again.
+    async fn put(&self, hash: &str, data: Bytes) -> Result<()>;
+
+    /// Read a whole blob into memory.
+    async fn get(&self, hash: &str) -> Result<Bytes> {
+        let mut stream = self.stream(hash).await?;
+        let mut data = Vec::with_capacity(stream.length as usize);
+        stream.reader.read_to_end(&mut data).await?;
+        Ok(Bytes::from(data))
+    }
+
+    /// Open a blob for streaming.
+    async fn stream(&self, hash: &str) -> Result<PackageStream>;
+    /// Open the bytes `start..end` of a blob for streaming.
+    async fn stream_range(&self, hash: &str, start: u64, end: u64) -> Result<PackageStream>;
+    async fn delete(&self, hash: &str) -> Result<()>;
+    async fn exists(&self, hash: &str) -> Result<bool>;
+
+    /// A time-limited URL clients can fetch the blob from directly, if the store supports it.
+    async fn url(&self, _hash: &str) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+/// Blob store on the local filesystem, under `blobs/sha256/` in a registry directory.
+pub struct FsBlobStore {
+    path: PathBuf,
+}
+
+impl FsBlobStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    fn blob_path(&self, hash: &str) -> Result<PathBuf> {
+        Ok(self.path.join(blob_key(hash)?))
+    }
+
+    /// Move a package stored by an older release at `extensions/{id}/versions/{v}/package.empkg`
+    /// into the blob store. Does nothing if there is no such file.
+    pub(crate) fn adopt_legacy_package(&self, id: &str, version: &semver::Version, hash: &str) -> Result<()> {
+        validate_hash(hash)?;
+        let legacy = self
+            .path
+            .join("extensions")
+            .join(id)
+            .join("versions")
+            .join(version.to_string())
+            .join("package.empkg");
+        if !legacy.exists() {
+            return Ok(());
+        }
+
+        let target = self.blob_path(hash)?;
+        if target.exists() {
+            std::fs::remove_file(&legacy)?;
+        } else {
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::rename(&legacy, &target)?;
+        }
+        info!("Moved {}@{} package into blob {}", id, version, hash);
+        Ok(())
+    }
+
+    async fn open_file(&self, hash: &str) -> Result<fs::File> {
+        fs::File::open(self.blob_path(hash)?).await.map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => blob_not_found(hash),
+            _ => e.into(),
+        })
+    }
+}
+
+#[async_trait]
+impl BlobStore for FsBlobStore {
+    async fn put(&self, hash: &str, data: Bytes) -> Result<()> {
+        let target = self.blob_path(hash)?;
+        verify_digest(hash, &data)?;
+        if fs::try_exists(&target).await? {
+            debug!("Blob {} already stored", hash);
+            return Ok(());
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let staging = self.path.join(".staging");
+        fs::create_dir_all(&staging).await?;
+        let staged = staging.join(format!("{}.{}.blob", hash, hex::encode(rand::random::<[u8; 8]>())));
+
+        if let Err(e) = write_and_rename(&staged, &target, &data).await {
+            let _ = fs::remove_file(&staged).await;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    async fn stream(&self, hash: &str) -> Result<PackageStream> {
+        let file = self.open_file(hash).await?;
+        let length = file.metadata().await?.len();
+        Ok(PackageStream {
+            reader: Box::new(file),
+            length,
+        })
+    }
+
+    async fn stream_range(&self, hash: &str, start: u64, end: u64) -> Result<PackageStream> {
+        let mut file = self.open_file(hash).await?;
+        let size = file.metadata().await?.len();
+        let end = end.min(size);
+        let start = start.min(end);
+        file.seek(std::io::SeekFrom::Start(start)).await?;
+        Ok(PackageStream {
+            reader: Box::new(file.take(end - start)),
+            length: end - start,
+        })
+    }
+
+    async fn delete(&self, hash: &str) -> Result<()> {
+        match fs::remove_file(self.blob_path(hash)?).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn exists(&self, hash: &str) -> Result<bool> {
+        Ok(fs::try_exists(self.blob_path(hash)?).await?)
+    }
+}
+
+/// Write `data` to `staged`, flush it and rename it over `target`.
+async fn write_and_rename(staged: &Path, target: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut file = fs::File::create(staged).await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    drop(file);
+    fs::rename(staged, target).await
+}
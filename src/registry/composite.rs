@@ -0,0 +1,291 @@
+//! Registry built from a metadata index and a blob store.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use jiff::Timestamp;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+use tokio::sync::{Mutex, MutexGuard};
+use tracing::{debug, info, warn};
+
+use crate::package::Manifest;
+use crate::registry::{validate_id, BlobStore, Index, PackageStream, Registry, RESERVED_IDS};
+use crate::types::{Details, ListOptions, Page, PublishOptions, Summary, Version};
+use crate::{Error, Result};
+
+/// A [`Registry`] that keeps metadata in an [`Index`] and package bytes in a [`BlobStore`].
+///
+/// Packages are stored by SHA-256 before their version is recorded, so the index never references a
+/// blob that is missing. Versions reference their blob by [`Version::checksum_sha256`]; identical
+/// packages published under several ids or versions are stored once.
+pub struct CompositeRegistry<I, B: ?Sized> {
+    index: I,
+    blobs: Arc<B>,
+    /// Held while storing blobs and recording the versions that reference them, so a blob is never
+    /// deleted between being stored and being referenced.
+    blob_lock: Mutex<()>,
+}
+
+/// Exclusive right to store, reference and release blobs, within this process and across processes
+/// sharing the index. Released on drop.
+pub(crate) struct BlobLock<'a> {
+    _file: Option<std::fs::File>,
+    _guard: MutexGuard<'a, ()>,
+}
+
+impl<I: Index, B: BlobStore + ?Sized> CompositeRegistry<I, B> {
+    pub fn from_parts(index: I, blobs: Arc<B>) -> Self {
+        Self {
+            index,
+            blobs,
+            blob_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn index(&self) -> &I {
+        &self.index
+    }
+
+    pub fn blobs(&self) -> &Arc<B> {
+        &self.blobs
+    }
+
+    /// Lock out blob deletion while storing blobs and recording the versions that reference them.
+    pub(crate) async fn lock_blobs(&self) -> Result<BlobLock<'_>> {
+        let guard = self.blob_lock.lock().await;
+        let file = self.index.lock_blobs().await?;
+        Ok(BlobLock { _file: file, _guard: guard })
+    }
+
+    /// Package hash of `id@version`, after checking that the version exists.
+    async fn blob_hash(&self, id: &str, version: &semver::Version) -> Result<String> {
+        Ok(self.index.get_version(id, version).await?.checksum_sha256)
+    }
+
+    /// Refuse to create an extension with one of the [`RESERVED_IDS`].
+    async fn check_reserved(&self, id: &str) -> Result<()> {
+        if !RESERVED_IDS.contains(&id) {
+            return Ok(());
+        }
+        match self.index.get_owners(id).await {
+            Ok(_) => Ok(()),
+            Err(Error::NotFound(_)) => Err(Error::BadRequest(format!("Extension id {:?} is reserved", id))),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Delete blob `hash` unless some version still references it. Callers hold [`Self::lock_blobs`].
+    async fn release_blob(&self, hash: &str) -> Result<()> {
+        if self.index.all_versions().await?.iter().any(|(_, v)| v.checksum_sha256 == hash) {
+            return Ok(());
+        }
+        self.blobs.delete(hash).await?;
+        debug!("Deleted unreferenced blob {}", hash);
+        Ok(())
+    }
+
+    /// Re-hash the package of every version and report those whose blob is missing or does not
+    /// match the recorded checksum and size. Shared blobs are read once.
+    pub async fn verify(&self) -> Result<Vec<Damaged>> {
+        let mut damaged = Vec::new();
+        let mut checked: HashMap<String, Option<String>> = HashMap::new();
+        for (id, version) in self.index.all_versions().await? {
+            let hash = &version.checksum_sha256;
+            if !checked.contains_key(hash) {
+                let problem = self.check_blob(hash, version.size_bytes).await?;
+                checked.insert(hash.clone(), problem);
+            }
+            if let Some(reason) = checked[hash].clone() {
+                damaged.push(Damaged {
+                    id,
+                    version: version.version,
+                    reason,
+                });
+            }
+        }
+        info!("Verified {} blobs, {} damaged versions", checked.len(), damaged.len());
+        Ok(damaged)
+    }
+
+    /// Describe what is wrong with blob `hash`, if anything.
+    async fn check_blob(&self, hash: &str, size: u64) -> Result<Option<String>> {
+        let mut stream = match self.blobs.stream(hash).await {
+            Ok(stream) => stream,
+            Err(Error::NotFound(_)) => return Ok(Some(format!("blob {} is missing", hash))),
+            Err(e) => return Err(e),
+        };
+        let mut hasher = Sha256::new();
+        let mut read = 0u64;
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = stream.reader.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            read += n as u64;
+        }
+        let actual = hex::encode(hasher.finalize());
+        Ok(if actual != hash {
+            Some(format!("blob {} has checksum {}", hash, actual))
+        } else if read != size {
+            Some(format!("blob {} is {} bytes, expected {}", hash, read, size))
+        } else {
+            None
+        })
+    }
+
+    async fn count_download(&self, id: &str, version: &semver::Version) {
+        if let Err(e) = self.index.record_download(id, version).await {
+            warn!("Failed to count download: {}", e);
+        }
+    }
+}
+
+/// A version whose package failed [`CompositeRegistry::verify`].
+#[derive(Debug, Clone)]
+pub struct Damaged {
+    pub id: String,
+    pub version: semver::Version,
+    pub reason: String,
+}
+
+/// Report a blob that is missing for an existing version as a missing version.
+fn missing_package(id: &str, version: &semver::Version) -> impl FnOnce(Error) -> Error {
+    let id = id.to_string();
+    let version = version.to_string();
+    move |e| match e {
+        Error::NotFound(what) => {
+            warn!("Package of {}@{} is missing: {}", id, version, what);
+            Error::VersionNotFound { id, version }
+        }
+        e => e,
+    }
+}
+
+#[async_trait]
+impl<I: Index, B: BlobStore + ?Sized> Registry for CompositeRegistry<I, B> {
+    async fn list(&self, options: ListOptions) -> Result<Page<Summary>> {
+        self.index.list(options).await
+    }
+
+    async fn get(&self, id: &str) -> Result<Details> {
+        self.index.get(id).await
+    }
+
+    async fn get_versions(&self, id: &str) -> Result<Vec<Version>> {
+        self.index.get_versions(id).await
+    }
+
+    async fn get_version(&self, id: &str, version: &semver::Version) -> Result<Version> {
+        self.index.get_version(id, version).await
+    }
+
+    async fn download(&self, id: &str, version: &semver::Version) -> Result<Bytes> {
+        let hash = self.blob_hash(id, version).await?;
+        let data = self.blobs.get(&hash).await.map_err(missing_package(id, version))?;
+        self.count_download(id, version).await;
+        debug!("Downloaded package: {}@{} ({} bytes)", id, version, data.len());
+        Ok(data)
+    }
+
+    async fn download_stream(&self, id: &str, version: &semver::Version) -> Result<PackageStream> {
+        let hash = self.blob_hash(id, version).await?;
+        let stream = self.blobs.stream(&hash).await.map_err(missing_package(id, version))?;
+        self.count_download(id, version).await;
+        debug!("Streaming package: {}@{} ({} bytes)", id, version, stream.length);
+        Ok(stream)
+    }
+
+    async fn download_range(&self, id: &str, version: &semver::Version, start: u64, end: u64) -> Result<PackageStream> {
+        let hash = self.blob_hash(id, version).await?;
+        let stream = self.blobs.stream_range(&hash, start, end).await.map_err(missing_package(id, version))?;
+        if start == 0 {
+            self.count_download(id, version).await;
+        }
+        debug!("Streaming package range: {}@{} bytes {}..{}", id, version, start, end);
+        Ok(stream)
+    }
+
+    async fn download_url(&self, id: &str, version: &semver::Version) -> Result<Option<String>> {
+        let hash = self.blob_hash(id, version).await?;
+        let url = self.blobs.url(&hash).await?;
+        if url.is_some() {
+            self.count_download(id, version).await;
+            debug!("Redirecting download: {}@{}", id, version);
+        }
+        Ok(url)
+    }
+
+    async fn publish(&self, package: Bytes, options: &PublishOptions) -> Result<Version> {
+        let manifest = Manifest::from_package(&package)?;
+        validate_id(&manifest.id)?;
+        self.check_reserved(&manifest.id).await?;
+        self.index.check_publish(&manifest, options).await?;
+
+        let version = Version {
+            version: manifest.version.clone(),
+            created_at: Timestamp::now(),
+            checksum_sha256: hex::encode(Sha256::digest(&package)),
+            size_bytes: package.len() as u64,
+            yanked: false,
+            yank_reason: None,
+            dependencies: manifest.dependencies.clone(),
+            host: manifest.host.clone(),
+        };
+        let _guard = self.lock_blobs().await?;
+        let replaced = if options.overwrite {
+            self.index.get_version(&manifest.id, &manifest.version).await.ok()
+        } else {
+            None
+        };
+        self.blobs.put(&version.checksum_sha256, package).await?;
+        if let Err(e) = self.index.insert_version(&manifest, &version, options).await {
+            if let Err(e) = self.release_blob(&version.checksum_sha256).await {
+                warn!("Failed to delete blob {} of failed publish: {}", version.checksum_sha256, e);
+            }
+            return Err(e);
+        }
+
+        if let Some(old) = replaced
+            && old.checksum_sha256 != version.checksum_sha256
+            && let Err(e) = self.release_blob(&old.checksum_sha256).await
+        {
+            warn!("Failed to delete replaced blob {}: {}", old.checksum_sha256, e);
+        }
+
+        info!("Published extension: {}@{}", manifest.id, version.version);
+        Ok(version)
+    }
+
+    async fn get_latest_version(&self, id: &str, host_version: Option<&semver::Version>) -> Result<Version> {
+        self.index.get_latest_version(id, host_version).await
+    }
+
+    async fn yank(&self, id: &str, version: &semver::Version, reason: Option<String>) -> Result<Version> {
+        let meta = self.index.set_yanked(id, version, true, reason).await?;
+        info!("Yanked extension: {}@{}", id, version);
+        Ok(meta)
+    }
+
+    async fn unyank(&self, id: &str, version: &semver::Version) -> Result<Version> {
+        let meta = self.index.set_yanked(id, version, false, None).await?;
+        info!("Unyanked extension: {}@{}", id, version);
+        Ok(meta)
+    }
+
+    async fn get_owners(&self, id: &str) -> Result<Vec<String>> {
+        self.index.get_owners(id).await
+    }
+
+    async fn add_owner(&self, id: &str, owner: &str) -> Result<Vec<String>> {
+        self.index.add_owner(id, owner).await
+    }
+
+    async fn remove_owner(&self, id: &str, owner: &str) -> Result<Vec<String>> {
+        self.index.remove_owner(id, owner).await
+    }
+}
@@ -0,0 +1,220 @@
+//! Download counting shared by the indexes.
+//!
+//! Downloads are counted in memory and saved in batches by a background task, so serving a download never
+//! waits on the disk or the database.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, warn};
+
+use crate::{Error, Result};
+
+/// How often counted downloads are saved.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Download counts of one extension.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Downloads {
+    pub total: u64,
+    /// Downloads by version.
+    #[serde(default)]
+    pub versions: BTreeMap<String, u64>,
+}
+
+impl Downloads {
+    pub(crate) fn add(&mut self, other: &Downloads) {
+        self.total += other.total;
+        for (version, count) in &other.versions {
+            *self.versions.entry(version.clone()).or_default() += count;
+        }
+    }
+}
+
+/// Where an index saves batches of counted downloads.
+pub(crate) trait DownloadStore: Send + Sync + 'static {
+    /// Add `batch` to the saved counts, removing the extensions that were saved from it. Whatever is left
+    /// is kept for the next flush. Runs on a blocking thread.
+    fn save(&self, batch: &mut HashMap<String, Downloads>) -> Result<()>;
+}
+
+/// Downloads counted in memory, saved to a [`DownloadStore`] every [`FLUSH_INTERVAL`] and when dropped.
+pub(crate) struct DownloadCounter<S: DownloadStore> {
+    shared: Arc<Shared<S>>,
+}
+
+struct Shared<S> {
+    store: S,
+    pending: Mutex<HashMap<String, Downloads>>,
+    /// Serializes flushes, so a batch that failed to save is retried in order.
+    flushing: Mutex<()>,
+}
+
+impl<S: DownloadStore> DownloadCounter<S> {
+    /// Create a counter saving to `store`. The background task needs a Tokio runtime; without one,
+    /// counts are only saved by [`Self::flush`] and on drop.
+    pub fn new(store: S) -> Self {
+        let shared = Arc::new(Shared {
+            store,
+            pending: Mutex::new(HashMap::new()),
+            flushing: Mutex::new(()),
+        });
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(flush_periodically(Arc::downgrade(&shared)));
+        }
+        Self { shared }
+    }
+
+    pub fn store(&self) -> &S {
+        &self.shared.store
+    }
+
+    /// Count a download of `id@version`.
+    pub fn record(&self, id: &str, version: &semver::Version) -> Result<()> {
+        let mut pending = self.shared.pending()?;
+        let counts = pending.entry(id.to_string()).or_default();
+        counts.total += 1;
+        *counts.versions.entry(version.to_string()).or_default() += 1;
+        Ok(())
+    }
+
+    /// Total downloads of each extension that are counted but not yet saved.
+    pub fn pending_totals(&self) -> Result<HashMap<String, u64>> {
+        Ok(self.shared.pending()?.iter().map(|(id, counts)| (id.clone(), counts.total)).collect())
+    }
+
+    /// Save the counted downloads now.
+    pub async fn flush(&self) -> Result<()> {
+        let shared = self.shared.clone();
+        tokio::task::spawn_blocking(move || shared.flush())
+            .await
+            .map_err(|e| Error::Internal(e.to_string()))?
+    }
+}
+
+impl<S: DownloadStore> Drop for DownloadCounter<S> {
+    fn drop(&mut self) {
+        if let Err(e) = self.shared.flush() {
+            warn!("Failed to save download counts: {}", e);
+        }
+    }
+}
+
+impl<S: DownloadStore> Shared<S> {
+    fn pending(&self) -> Result<MutexGuard<'_, HashMap<String, Downloads>>> {
+        self.pending.lock().map_err(|_| Error::Internal("Download counts poisoned".into()))
+    }
+
+    fn flush(&self) -> Result<()> {
+        let _flushing = self.flushing.lock().map_err(|_| Error::Internal("Download flush poisoned".into()))?;
+        let mut batch = std::mem::take(&mut *self.pending()?);
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let extensions = batch.len();
+        let result = self.store.save(&mut batch);
+        debug!("Saved downloads of {} extensions", extensions - batch.len());
+        if !batch.is_empty() {
+            let mut pending = self.pending()?;
+            for (id, counts) in batch {
+                pending.entry(id).or_default().add(&counts);
+            }
+        }
+        result
+    }
+}
+
+/// Flush `shared` every [`FLUSH_INTERVAL`] until its counter is dropped.
+async fn flush_periodically<S: DownloadStore>(shared: Weak<Shared<S>>) {
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // The first tick completes immediately.
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        match tokio::task::spawn_blocking(move || shared.flush()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Failed to save download counts: {}", e),
+            Err(e) => warn!("Download flush task failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Saves into memory, failing for ids in `failing`.
+    #[derive(Default)]
+    struct Memory {
+        saved: Mutex<HashMap<String, Downloads>>,
+        failing: Mutex<Vec<String>>,
+    }
+
+    impl DownloadStore for Arc<Memory> {
+        fn save(&self, batch: &mut HashMap<String, Downloads>) -> Result<()> {
+            let failing = self.failing.lock().unwrap();
+            let mut saved = self.saved.lock().unwrap();
+            batch.retain(|id, counts| {
+                if failing.contains(id) {
+                    return true;
+                }
+                saved.entry(id.clone()).or_default().add(counts);
+                false
+            });
+            match failing.is_empty() {
+                true => Ok(()),
+                false => Err(Error::Internal("unavailable".into())),
+            }
+        }
+    }
+
+    fn total(store: &Memory, id: &str) -> u64 {
+        store.saved.lock().unwrap().get(id).map_or(0, |d| d.total)
+    }
+
+    #[test]
+    fn saves_on_flush_and_drop() {
+        let store = Arc::new(Memory::default());
+        let counter = DownloadCounter::new(store.clone());
+        let v1 = semver::Version::new(1, 0, 0);
+        counter.record("a", &v1).unwrap();
+        counter.record("a", &v1).unwrap();
+        assert_eq!(counter.pending_totals().unwrap()["a"], 2);
+        assert_eq!(total(&store, "a"), 0);
+
+        counter.shared.flush().unwrap();
+        assert!(counter.pending_totals().unwrap().is_empty());
+        assert_eq!(total(&store, "a"), 2);
+
+        counter.record("a", &v1).unwrap();
+        drop(counter);
+        assert_eq!(total(&store, "a"), 3);
+        assert_eq!(store.saved.lock().unwrap()["a"].versions["1.0.0"], 3);
+    }
+
+    #[test]
+    fn keeps_unsaved_counts() {
+        let store = Arc::new(Memory::default());
+        store.failing.lock().unwrap().push("b".into());
+        let counter = DownloadCounter::new(store.clone());
+        let v1 = semver::Version::new(1, 0, 0);
+        counter.record("a", &v1).unwrap();
+        counter.record("b", &v1).unwrap();
+
+        assert!(counter.shared.flush().is_err());
+        assert_eq!(total(&store, "a"), 1);
+        assert_eq!(counter.pending_totals().unwrap(), HashMap::from([("b".to_string(), 1)]));
+
+        counter.record("b", &v1).unwrap();
+        store.failing.lock().unwrap().clear();
+        counter.shared.flush().unwrap();
+        assert_eq!(total(&store, "b"), 2);
+    }
+}
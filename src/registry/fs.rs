@@ -1,26 +1,337 @@
 //! Filesystem-based registry implementation.
 
-use std::path::PathBuf;
+mod cache;
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
 
 use async_trait::async_trait;
-use bytes::Bytes;
-use jiff::Timestamp;
-use sha2::{Digest, Sha256};
 use tokio::fs;
-use tracing::{debug, info};
-
-use crate::registry::{Meta, Registry};
-use crate::types::{ListOptions, Page, Summary, Version};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{OwnedMutexGuard, RwLock, RwLockReadGuard};
+use tracing::{debug, info, warn};
+
+use crate::package::Manifest;
+use crate::registry::blob::FsBlobStore;
+use crate::registry::downloads::{DownloadCounter, DownloadStore, Downloads};
+use crate::registry::search::{self, Candidate, Document};
+use crate::registry::{
+    latest_compatible, lock_file, lock_file_blocking, no_latest_version, representative_manifest, CompositeRegistry, Index, Meta,
+};
+use crate::types::{Details, ListOptions, Page, PublishOptions, Summary, Version};
 use crate::{Error, Result};
 
-/// Filesystem-based registry.
-pub struct FilesystemRegistry {
+use cache::{Cache, Entry, Stamp};
+
+/// Staging entries older than this are assumed to belong to a crashed publish.
+const STALE_STAGING_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Filesystem-based registry: a [`FsIndex`] with packages in a [`FsBlobStore`] in the same directory.
+pub type FilesystemRegistry = CompositeRegistry<FsIndex, FsBlobStore>;
+
+impl FilesystemRegistry {
+    pub fn new(path: PathBuf) -> Self {
+        let index = FsIndex::new(path.clone());
+        let blobs = FsBlobStore::new(path);
+        for (id, version, hash) in index.legacy_packages() {
+            if let Err(e) = blobs.adopt_legacy_package(&id, &version, &hash) {
+                warn!("Failed to migrate package of {}@{}: {}", id, version, e);
+            }
+        }
+        Self::from_parts(index, Arc::new(blobs))
+    }
+}
+
+/// Filesystem metadata index.
+///
+/// Versions are published by staging their `meta.json` and `manifest.json` in `.staging/` and
+/// renaming the finished directory into place, so a version is either fully present or absent.
+///
+/// Writes to an extension are serialized by an in-process mutex plus an advisory file lock in
+/// `.locks/`, so several processes can safely share one registry directory.
+///
+/// Reads are served from an in-memory cache that is built on first use and updated on every write.
+/// Writes from other processes bump a generation marker, which makes this process rebuild the cache.
+///
+/// Downloads are counted in memory and added to each extension's `downloads.json` in the background
+/// every [`FLUSH_INTERVAL`](crate::registry::downloads::FLUSH_INTERVAL) and when the index is dropped.
+/// Counts not yet written are lost if the process is killed.
+pub struct FsIndex {
     path: PathBuf,
+    locks: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    cache: RwLock<Option<Cache>>,
+    downloads: DownloadCounter<FsDownloads>,
 }
 
-impl FilesystemRegistry {
+/// Saves download counts to `downloads.json`, under the same file lock as other writes to the extension.
+struct FsDownloads {
+    extensions_dir: PathBuf,
+    locks_dir: PathBuf,
+    /// Totals written by this process, which are newer than the cache until it is rebuilt.
+    saved: Mutex<HashMap<String, u64>>,
+}
+
+impl FsDownloads {
+    fn save_one(&self, id: &str, counts: &Downloads) -> Result<u64> {
+        let _lock = lock_file_blocking(&self.locks_dir.join(format!("{}.lock", id)))?;
+        let path = self.extensions_dir.join(id).join("downloads.json");
+        let mut downloads: Downloads = match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_default(),
+            Err(_) => Downloads::default(),
+        };
+        downloads.add(counts);
+        write_atomic_blocking(&path, serde_json::to_string(&downloads)?.as_bytes())?;
+        Ok(downloads.total)
+    }
+
+    fn saved(&self) -> Result<MutexGuard<'_, HashMap<String, u64>>> {
+        self.saved.lock().map_err(|_| Error::Internal("Saved download counts poisoned".into()))
+    }
+}
+
+impl DownloadStore for FsDownloads {
+    fn save(&self, batch: &mut HashMap<String, Downloads>) -> Result<()> {
+        let mut failed = None;
+        let mut saved = Vec::new();
+        batch.retain(|id, counts| match self.save_one(id, counts) {
+            Ok(total) => {
+                saved.push((id.clone(), total));
+                false
+            }
+            Err(e) => {
+                warn!("Failed to save {} downloads of {}: {}", counts.total, id, e);
+                failed = Some(e);
+                true
+            }
+        });
+        self.saved()?.extend(saved);
+        failed.map_or(Ok(()), Err)
+    }
+}
+
+/// Everything stored for one version, for migrating to another backend.
+pub struct ExportedVersion {
+    pub info: Version,
+    pub manifest: Option<Manifest>,
+}
+
+/// Everything stored for one extension, for migrating to another backend.
+pub struct ExportedExtension {
+    pub meta: Meta,
+    pub versions: Vec<ExportedVersion>,
+    pub downloads: Downloads,
+}
+
+/// Exclusive write access to one extension. Released on drop.
+struct ExtensionLock {
+    _file: std::fs::File,
+    _guard: OwnedMutexGuard<()>,
+}
+
+impl FsIndex {
     pub fn new(path: PathBuf) -> Self {
-        Self { path }
+        let index = Self {
+            locks: Mutex::new(HashMap::new()),
+            cache: RwLock::new(None),
+            downloads: DownloadCounter::new(FsDownloads {
+                extensions_dir: path.join("extensions"),
+                locks_dir: path.join(".locks"),
+                saved: Mutex::new(HashMap::new()),
+            }),
+            path,
+        };
+        index.cleanup_interrupted();
+        index
+    }
+
+    /// Read the whole registry from disk, newest versions first.
+    pub async fn export(&self) -> Result<Vec<ExportedExtension>> {
+        self.flush_downloads().await?;
+        let mut extensions = Vec::new();
+        for id in self.list_extension_ids().await? {
+            let Some(entry) = self.load_entry(&id).await? else {
+                continue;
+            };
+            let mut versions = Vec::new();
+            for info in entry.versions {
+                versions.push(ExportedVersion {
+                    manifest: self.read_version_manifest(&id, &info.version).await,
+                    info,
+                });
+            }
+            extensions.push(ExportedExtension {
+                meta: entry.meta,
+                versions,
+                downloads: self.read_downloads(&id).await,
+            });
+        }
+        Ok(extensions)
+    }
+
+    /// Add the downloads counted in memory to each extension's `downloads.json` now, rather than
+    /// waiting for the background flush. Counts that fail to save are kept for the next flush.
+    pub async fn flush_downloads(&self) -> Result<()> {
+        self.downloads.flush().await
+    }
+
+    fn locks_dir(&self) -> PathBuf {
+        self.path.join(".locks")
+    }
+
+    /// Lock file guarding blob deletion. Extension ids cannot start with a dot, so it cannot clash with
+    /// an extension's lock.
+    fn blobs_lock_path(&self) -> PathBuf {
+        self.locks_dir().join(".blobs.lock")
+    }
+
+    /// Acquire the write lock for extension `id`, first within this process and then across processes.
+    async fn lock(&self, id: &str) -> Result<ExtensionLock> {
+        let mutex = {
+            let mut locks = self.locks.lock().map_err(|_| Error::Internal("Lock map poisoned".into()))?;
+            locks.entry(id.to_string()).or_default().clone()
+        };
+        let guard = mutex.lock_owned().await;
+        let file = lock_file(self.locks_dir().join(format!("{}.lock", id))).await?;
+        Ok(ExtensionLock { _file: file, _guard: guard })
+    }
+
+    fn staging_dir(&self) -> PathBuf {
+        self.path.join(".staging")
+    }
+
+    /// Remove leftovers of writes that were interrupted before they could be renamed into place: staged
+    /// publishes and the temporary files of [`write_atomic`].
+    fn cleanup_interrupted(&self) {
+        if let Ok(entries) = std::fs::read_dir(self.staging_dir()) {
+            for entry in entries.flatten() {
+                if !is_stale(&entry) {
+                    continue;
+                }
+                let path = entry.path();
+                let removed = if path.is_dir() { std::fs::remove_dir_all(&path) } else { std::fs::remove_file(&path) };
+                match removed {
+                    Ok(()) => info!("Removed interrupted publish: {}", path.display()),
+                    Err(e) => warn!("Failed to remove {}: {}", path.display(), e),
+                }
+            }
+        }
+
+        remove_stale_temp_files(&self.path);
+        let Ok(extensions) = std::fs::read_dir(self.extensions_dir()) else {
+            return;
+        };
+        for extension in extensions.flatten() {
+            remove_stale_temp_files(&extension.path());
+            let Ok(versions) = std::fs::read_dir(extension.path().join("versions")) else {
+                continue;
+            };
+            for version in versions.flatten() {
+                remove_stale_temp_files(&version.path());
+            }
+        }
+    }
+
+    fn generation_path(&self) -> PathBuf {
+        self.path.join(".generation")
+    }
+
+    /// Current change marker of the registry directory.
+    async fn stamp(&self) -> Stamp {
+        Stamp {
+            generation: fs::read_to_string(self.generation_path()).await.ok(),
+            modified: fs::metadata(self.extensions_dir()).await.and_then(|m| m.modified()).ok(),
+        }
+    }
+
+    /// Load the metadata of one extension from disk. Extensions without a readable `meta.json` are skipped.
+    async fn load_entry(&self, id: &str) -> Result<Option<Entry>> {
+        let meta = match self.read_extension_meta(id).await {
+            Ok(meta) => meta,
+            Err(_) => return Ok(None),
+        };
+        let mut versions = Vec::new();
+        for v in self.list_versions(id).await? {
+            if let Ok(version) = self.read_version_meta(id, &v).await {
+                versions.push(version);
+            }
+        }
+        Ok(Some(Entry {
+            document: Document::new(&meta),
+            meta,
+            versions,
+            downloads: self.read_downloads(id).await.total,
+        }))
+    }
+
+    async fn build_cache(&self, stamp: Stamp) -> Result<Cache> {
+        let mut entries = BTreeMap::new();
+        for id in self.list_extension_ids().await? {
+            if let Some(entry) = self.load_entry(&id).await? {
+                entries.insert(id, entry);
+            }
+        }
+        debug!("Indexed {} extensions", entries.len());
+        Ok(Cache { stamp, entries })
+    }
+
+    /// Up-to-date cache, rebuilt if the registry changed behind our back.
+    async fn cache(&self) -> Result<RwLockReadGuard<'_, Cache>> {
+        let stamp = self.stamp().await;
+        {
+            let guard = self.cache.read().await;
+            if guard.as_ref().is_some_and(|cache| cache.stamp == stamp) {
+                return Ok(RwLockReadGuard::map(guard, |cache| cache.as_ref().expect("cache checked above")));
+            }
+        }
+
+        let mut guard = self.cache.write().await;
+        if !guard.as_ref().is_some_and(|cache| cache.stamp == stamp) {
+            *guard = Some(self.build_cache(stamp).await?);
+        }
+        Ok(RwLockReadGuard::map(guard.downgrade(), |cache| cache.as_ref().expect("cache built above")))
+    }
+
+    async fn entry(&self, id: &str) -> Result<Entry> {
+        self.cache()
+            .await?
+            .entries
+            .get(id)
+            .cloned()
+            .ok_or_else(|| Error::NotFound(format!("Extension {}", id)))
+    }
+
+    /// Record a write to extension `id`: bump the generation marker for other processes and
+    /// refresh our own cache entry.
+    async fn reindex(&self, id: &str) -> Result<()> {
+        let before = self.stamp().await;
+        write_atomic(&self.generation_path(), hex::encode(rand::random::<[u8; 16]>()).as_bytes()).await?;
+        let after = self.stamp().await;
+        let entry = self.load_entry(id).await?;
+
+        let mut guard = self.cache.write().await;
+        match guard.as_mut() {
+            Some(cache) if cache.stamp == before => {
+                match entry {
+                    Some(entry) => cache.entries.insert(id.to_string(), entry),
+                    None => cache.entries.remove(id),
+                };
+                cache.stamp = after;
+            }
+            // Someone else changed the registry too; rebuild from scratch on next read.
+            _ => *guard = None,
+        }
+        Ok(())
+    }
+
+    /// Drop the cache of this process and, if possible, of other processes, after a write whose
+    /// [`Self::reindex`] could not complete.
+    async fn invalidate(&self) {
+        *self.cache.write().await = None;
+        if let Err(e) = write_atomic(&self.generation_path(), hex::encode(rand::random::<[u8; 16]>()).as_bytes()).await {
+            warn!("Failed to bump the generation marker: {}", e);
+        }
     }
 
     fn extensions_dir(&self) -> PathBuf {
@@ -35,6 +346,10 @@ impl FilesystemRegistry {
         self.extension_dir(id).join("meta.json")
     }
 
+    fn downloads_path(&self, id: &str) -> PathBuf {
+        self.extension_dir(id).join("downloads.json")
+    }
+
     fn versions_dir(&self, id: &str) -> PathBuf {
         self.extension_dir(id).join("versions")
     }
@@ -47,8 +362,35 @@ impl FilesystemRegistry {
         self.version_dir(id, version).join("meta.json")
     }
 
-    fn package_path(&self, id: &str, version: &semver::Version) -> PathBuf {
-        self.version_dir(id, version).join("package.empkg")
+    fn version_manifest_path(&self, id: &str, version: &semver::Version) -> PathBuf {
+        self.version_dir(id, version).join("manifest.json")
+    }
+
+    /// Versions whose package still sits in the version directory, as written by older releases,
+    /// with the hash the package belongs under.
+    fn legacy_packages(&self) -> Vec<(String, semver::Version, String)> {
+        let mut packages = Vec::new();
+        let Ok(extensions) = std::fs::read_dir(self.extensions_dir()) else {
+            return packages;
+        };
+        for extension in extensions.flatten() {
+            let Some(id) = extension.file_name().to_str().map(String::from) else {
+                continue;
+            };
+            let Ok(versions) = std::fs::read_dir(self.versions_dir(&id)) else {
+                continue;
+            };
+            for dir in versions.flatten() {
+                if !dir.path().join("package.empkg").exists() {
+                    continue;
+                }
+                let meta = std::fs::read_to_string(dir.path().join("meta.json")).ok();
+                if let Some(meta) = meta.and_then(|m| serde_json::from_str::<Version>(&m).ok()) {
+                    packages.push((id.clone(), meta.version, meta.checksum_sha256));
+                }
+            }
+        }
+        packages
     }
 
     async fn read_extension_meta(&self, id: &str) -> Result<Meta> {
@@ -59,6 +401,18 @@ impl FilesystemRegistry {
         Ok(serde_json::from_str(&content)?)
     }
 
+    /// Download counts of `id`; zero if none were recorded or the file is unreadable.
+    async fn read_downloads(&self, id: &str) -> Downloads {
+        match fs::read_to_string(self.downloads_path(id)).await {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_default(),
+            Err(_) => Downloads::default(),
+        }
+    }
+
+    async fn write_extension_meta(&self, meta: &Meta) -> Result<()> {
+        write_atomic(&self.extension_meta_path(&meta.id), serde_json::to_string_pretty(meta)?.as_bytes()).await
+    }
+
     async fn read_version_meta(&self, id: &str, version: &semver::Version) -> Result<Version> {
         let path = self.version_meta_path(id, version);
         let content = fs::read_to_string(&path)
@@ -70,6 +424,95 @@ impl FilesystemRegistry {
         Ok(serde_json::from_str(&content)?)
     }
 
+    async fn write_version_meta(&self, id: &str, meta: &Version) -> Result<()> {
+        write_atomic(&self.version_meta_path(id, &meta.version), serde_json::to_string_pretty(meta)?.as_bytes()).await
+    }
+
+    /// Write every file of a version into `staging`.
+    async fn stage_version(&self, staging: &Path, meta: &Version, manifest: &Manifest) -> Result<()> {
+        fs::create_dir_all(staging).await?;
+        write_synced(&staging.join("meta.json"), serde_json::to_string_pretty(meta)?.as_bytes()).await?;
+        write_synced(&staging.join("manifest.json"), serde_json::to_string_pretty(manifest)?.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Atomically move a staged version directory into place, replacing any existing one.
+    async fn commit_version(&self, staging: &Path, id: &str, version: &semver::Version) -> Result<()> {
+        fs::create_dir_all(self.versions_dir(id)).await?;
+        let target = self.version_dir(id, version);
+
+        let mut replaced = staging.as_os_str().to_owned();
+        replaced.push(".old");
+        let replaced = PathBuf::from(replaced);
+        let had_existing = match fs::rename(&target, &replaced).await {
+            Ok(()) => true,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+            Err(e) => return Err(e.into()),
+        };
+        if let Err(e) = fs::rename(staging, &target).await {
+            if had_existing {
+                let _ = fs::rename(&replaced, &target).await;
+            }
+            return Err(e.into());
+        }
+        if had_existing {
+            fs::remove_dir_all(&replaced).await?;
+        }
+        Ok(())
+    }
+
+    async fn read_version_manifest(&self, id: &str, version: &semver::Version) -> Option<Manifest> {
+        let content = fs::read_to_string(self.version_manifest_path(id, version)).await.ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    /// Rebuild the extension-level `meta.json` from the manifest of the version that should
+    /// represent the extension (see [`representative_manifest`]). Owners are preserved.
+    async fn refresh_extension_meta(&self, id: &str) -> Result<()> {
+        let mut versions = Vec::new();
+        for v in self.list_versions(id).await? {
+            if let Some(manifest) = self.read_version_manifest(id, &v).await
+                && let Ok(info) = self.read_version_meta(id, &v).await
+            {
+                versions.push((info, manifest));
+            }
+        }
+
+        let Some(manifest) = representative_manifest(versions) else {
+            return Ok(());
+        };
+        let owners = match self.read_extension_meta(id).await {
+            Ok(meta) => meta.owners,
+            Err(_) => Vec::new(),
+        };
+        self.write_extension_meta(&Meta::from_manifest(&manifest, owners)).await
+    }
+
+    /// Check ownership and immutability for a publish. Returns the existing extension metadata, if any.
+    async fn check_publisher(&self, manifest: &Manifest, options: &PublishOptions) -> Result<Option<Meta>> {
+        let id = manifest.id.as_str();
+        let existing = match self.read_extension_meta(id).await {
+            Ok(meta) => Some(meta),
+            Err(Error::NotFound(_)) => None,
+            Err(e) => return Err(e),
+        };
+        if let Some(ref meta) = existing
+            && !options.admin
+            && !meta.owners.iter().any(|o| o == &options.publisher)
+        {
+            return Err(Error::Forbidden(format!("{} is not an owner of {}", options.publisher, id)));
+        }
+        // Versions of an extension without `meta.json` are leftovers of a crashed first publish, which
+        // the retry replaces.
+        if existing.is_some() && !options.overwrite && fs::try_exists(self.version_dir(id, &manifest.version)).await? {
+            return Err(Error::VersionExists {
+                id: id.to_string(),
+                version: manifest.version.to_string(),
+            });
+        }
+        Ok(existing)
+    }
+
     async fn list_extension_ids(&self) -> Result<Vec<String>> {
         let dir = self.extensions_dir();
         if !dir.exists() {
@@ -79,10 +522,10 @@ impl FilesystemRegistry {
         let mut ids = Vec::new();
         let mut entries = fs::read_dir(&dir).await?;
         while let Some(entry) = entries.next_entry().await? {
-            if entry.file_type().await?.is_dir() {
-                if let Some(name) = entry.file_name().to_str() {
-                    ids.push(name.to_string());
-                }
+            if entry.file_type().await?.is_dir()
+                && let Some(name) = entry.file_name().to_str()
+            {
+                ids.push(name.to_string());
             }
         }
         ids.sort();
@@ -98,12 +541,11 @@ impl FilesystemRegistry {
         let mut versions = Vec::new();
         let mut entries = fs::read_dir(&dir).await?;
         while let Some(entry) = entries.next_entry().await? {
-            if entry.file_type().await?.is_dir() {
-                if let Some(name) = entry.file_name().to_str() {
-                    if let Ok(v) = semver::Version::parse(name) {
-                        versions.push(v);
-                    }
-                }
+            if entry.file_type().await?.is_dir()
+                && let Some(name) = entry.file_name().to_str()
+                && let Ok(v) = semver::Version::parse(name)
+            {
+                versions.push(v);
             }
         }
         versions.sort();
@@ -113,158 +555,214 @@ impl FilesystemRegistry {
 }
 
 #[async_trait]
-impl Registry for FilesystemRegistry {
+impl Index for FsIndex {
     async fn list(&self, options: ListOptions) -> Result<Page<Summary>> {
-        let ids = self.list_extension_ids().await?;
-        let mut summaries = Vec::new();
-
-        for id in &ids {
-            let meta = match self.read_extension_meta(id).await {
-                Ok(m) => m,
-                Err(_) => continue,
-            };
-
-            if let Some(ref query) = options.query {
-                let q = query.to_lowercase();
-                if !meta.name.to_lowercase().contains(&q)
-                    && !meta.description.to_lowercase().contains(&q)
-                    && !meta.id.to_lowercase().contains(&q)
-                {
-                    continue;
-                }
-            }
+        let cache = self.cache().await?;
+        let saved = self.downloads.store().saved()?.clone();
+        let pending = self.downloads.pending_totals()?;
+        let candidates = cache.entries.iter().map(|(id, e)| Candidate {
+            meta: &e.meta,
+            document: &e.document,
+            versions: &e.versions,
+            downloads: e.downloads.max(saved.get(id).copied().unwrap_or(0)) + pending.get(id).copied().unwrap_or(0),
+        });
+        Ok(search::list(candidates, &options))
+    }
 
-            if let Some(ref category) = options.category {
-                if !meta.categories.iter().any(|c| c.eq_ignore_ascii_case(category)) {
-                    continue;
-                }
-            }
+    async fn get(&self, id: &str) -> Result<Details> {
+        let entry = self.entry(id).await?;
+        let latest = entry.latest_unyanked()
+            .or(entry.versions.first())
+            .ok_or_else(|| Error::NotFound(format!("Extension {}", id)))?;
+        let version_strings: Vec<String> = entry.versions.iter().map(|v| v.version.to_string()).collect();
+        Ok(entry.meta.to_details(latest, version_strings))
+    }
 
-            let versions = self.list_versions(id).await?;
-            if let Some(latest) = versions.first() {
-                if let Ok(version_meta) = self.read_version_meta(id, latest).await {
-                    summaries.push(meta.to_summary(&version_meta));
-                }
-            }
-        }
+    async fn get_versions(&self, id: &str) -> Result<Vec<Version>> {
+        Ok(self.entry(id).await?.versions)
+    }
 
-        let total = summaries.len() as u32;
-        let page = options.page.max(1);
-        let per_page = options.per_page.clamp(1, 100);
-        let start = ((page - 1) * per_page) as usize;
-        let items: Vec<_> = summaries.into_iter().skip(start).take(per_page as usize).collect();
+    async fn get_version(&self, id: &str, version: &semver::Version) -> Result<Version> {
+        self.entry(id).await?.version(version).cloned().ok_or_else(|| Error::VersionNotFound {
+            id: id.to_string(),
+            version: version.to_string(),
+        })
+    }
 
-        Ok(Page::new(items, total, page, per_page))
+    async fn get_latest_version(&self, id: &str, host_version: Option<&semver::Version>) -> Result<Version> {
+        let entry = self.entry(id).await?;
+        latest_compatible(&entry.versions, host_version)
+            .cloned()
+            .ok_or_else(|| no_latest_version(id, host_version))
     }
 
-    async fn get(&self, id: &str) -> Result<crate::types::Details> {
-        let meta = self.read_extension_meta(id).await?;
-        let versions = self.list_versions(id).await?;
-        let latest = versions.first().ok_or_else(|| Error::NotFound(format!("Extension {}", id)))?;
-        let latest_meta = self.read_version_meta(id, latest).await?;
-        let version_strings: Vec<String> = versions.iter().map(|v| v.to_string()).collect();
-        Ok(meta.to_details(&latest_meta, version_strings))
+    async fn check_publish(&self, manifest: &Manifest, options: &PublishOptions) -> Result<()> {
+        self.check_publisher(manifest, options).await.map(|_| ())
     }
 
-    async fn get_versions(&self, id: &str) -> Result<Vec<Version>> {
-        let _ = self.read_extension_meta(id).await?;
-        let versions = self.list_versions(id).await?;
-        let mut result = Vec::new();
-        for v in versions {
-            if let Ok(meta) = self.read_version_meta(id, &v).await {
-                result.push(meta);
+    async fn insert_version(&self, manifest: &Manifest, version_meta: &Version, options: &PublishOptions) -> Result<()> {
+        let id = manifest.id.as_str();
+        let version = &version_meta.version;
+        let _lock = self.lock(id).await?;
+
+        let existing = self.check_publisher(manifest, options).await?;
+        if fs::try_exists(self.version_dir(id, version)).await? {
+            warn!("Overwriting existing version: {}@{}", id, version);
+        }
+
+        // A new extension gets its `meta.json` before its first version, so a crash in between leaves an
+        // extension without versions that the publisher can retry, never versions nobody owns.
+        if existing.is_none() {
+            fs::create_dir_all(self.extension_dir(id)).await?;
+            let meta = Meta::from_manifest(manifest, vec![options.publisher.clone()]);
+            self.write_extension_meta(&meta).await?;
+        }
+
+        let staging = self.staging_dir().join(format!("{}-{}-{}", id, version, hex::encode(rand::random::<[u8; 8]>())));
+        let staged = match self.stage_version(&staging, version_meta, manifest).await {
+            Ok(()) => self.commit_version(&staging, id, version).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = staged {
+            let _ = fs::remove_dir_all(&staging).await;
+            if existing.is_none() {
+                let _ = fs::remove_file(self.extension_meta_path(id)).await;
             }
+            return Err(e);
         }
-        Ok(result)
+
+        // The version is in place now. If updating the extension fails, readers must still see it,
+        // or the publish would be treated as failed and its blob released.
+        let refreshed = match self.refresh_extension_meta(id).await {
+            Ok(()) => self.reindex(id).await,
+            Err(e) => Err(e),
+        };
+        if refreshed.is_err() {
+            self.invalidate().await;
+        }
+        refreshed
     }
 
-    async fn get_version(&self, id: &str, version: &semver::Version) -> Result<Version> {
+    async fn set_yanked(&self, id: &str, version: &semver::Version, yanked: bool, reason: Option<String>) -> Result<Version> {
+        let _lock = self.lock(id).await?;
         let _ = self.read_extension_meta(id).await?;
-        self.read_version_meta(id, version).await
+        let mut meta = self.read_version_meta(id, version).await?;
+        meta.yanked = yanked;
+        meta.yank_reason = reason;
+        self.write_version_meta(id, &meta).await?;
+        self.refresh_extension_meta(id).await?;
+        self.reindex(id).await?;
+        Ok(meta)
     }
 
-    async fn download(&self, id: &str, version: &semver::Version) -> Result<Bytes> {
-        let _ = self.read_version_meta(id, version).await?;
-        let path = self.package_path(id, version);
-        let content = fs::read(&path).await.map_err(|_| Error::VersionNotFound {
-            id: id.to_string(),
-            version: version.to_string(),
-        })?;
-        debug!("Downloaded package: {}@{} ({} bytes)", id, version, content.len());
-        Ok(Bytes::from(content))
-    }
-
-    async fn publish(&self, package: Bytes) -> Result<()> {
-        let cursor = std::io::Cursor::new(&package);
-        let decoder = flate2::read::GzDecoder::new(cursor);
-        let mut archive = tar::Archive::new(decoder);
-
-        let mut manifest: Option<serde_json::Value> = None;
-        for entry in archive.entries().map_err(|e| Error::InvalidPackage(e.to_string()))? {
-            let mut entry = entry.map_err(|e| Error::InvalidPackage(e.to_string()))?;
-            let path = entry.path().map_err(|e| Error::InvalidPackage(e.to_string()))?;
-            if path.ends_with("manifest.json") {
-                let mut content = String::new();
-                std::io::Read::read_to_string(&mut entry, &mut content)
-                    .map_err(|e| Error::InvalidPackage(e.to_string()))?;
-                manifest = Some(serde_json::from_str(&content)?);
-                break;
-            }
-        }
+    async fn get_owners(&self, id: &str) -> Result<Vec<String>> {
+        Ok(self.entry(id).await?.meta.owners)
+    }
 
-        let manifest = manifest.ok_or_else(|| Error::InvalidPackage("Missing manifest.json".into()))?;
-        let id = manifest["id"].as_str().ok_or_else(|| Error::InvalidPackage("Missing id".into()))?;
-        let version_str = manifest["version"].as_str().ok_or_else(|| Error::InvalidPackage("Missing version".into()))?;
-        let version = semver::Version::parse(version_str).map_err(|e| Error::InvalidVersion(e.to_string()))?;
+    async fn lock_blobs(&self) -> Result<Option<std::fs::File>> {
+        Ok(Some(lock_file(self.blobs_lock_path()).await?))
+    }
 
-        let mut hasher = Sha256::new();
-        hasher.update(&package);
-        let checksum = hex::encode(hasher.finalize());
+    async fn all_versions(&self) -> Result<Vec<(String, Version)>> {
+        let cache = self.cache().await?;
+        Ok(cache
+            .entries
+            .iter()
+            .flat_map(|(id, entry)| entry.versions.iter().map(move |v| (id.clone(), v.clone())))
+            .collect())
+    }
 
-        let version_dir = self.version_dir(id, &version);
-        fs::create_dir_all(&version_dir).await?;
+    /// Counts are kept in memory and written by a background task. Writing them does not bump the
+    /// generation marker, so other processes only see them when they next rebuild their cache.
+    async fn record_download(&self, id: &str, version: &semver::Version) -> Result<()> {
+        self.downloads.record(id, version)
+    }
 
-        let meta_path = self.extension_meta_path(id);
-        if !meta_path.exists() {
-            let meta = Meta {
-                id: id.to_string(),
-                name: manifest["name"].as_str().unwrap_or(id).to_string(),
-                description: manifest["description"].as_str().unwrap_or("").to_string(),
-                author: manifest["author"].as_str().unwrap_or("").to_string(),
-                license: manifest["license"].as_str().unwrap_or("MIT").to_string(),
-                categories: extract_strings(&manifest["categories"]),
-                keywords: extract_strings(&manifest["keywords"]),
-                homepage: manifest["homepage"].as_str().map(String::from),
-                repository: manifest["repository"].as_str().map(String::from),
-                capabilities: extract_strings(&manifest["capabilities"]),
-                config_schema: manifest.get("config_schema").cloned(),
-                operations: extract_strings(&manifest["operations"]),
-            };
-            fs::write(&meta_path, serde_json::to_string_pretty(&meta)?).await?;
+    async fn add_owner(&self, id: &str, owner: &str) -> Result<Vec<String>> {
+        let _lock = self.lock(id).await?;
+        let mut meta = self.read_extension_meta(id).await?;
+        if !meta.owners.iter().any(|o| o == owner) {
+            meta.owners.push(owner.to_string());
+            self.write_extension_meta(&meta).await?;
+            self.reindex(id).await?;
+            info!("Added owner {} to {}", owner, id);
         }
+        Ok(meta.owners)
+    }
 
-        let version_meta = Version {
-            version: version.clone(),
-            created_at: Timestamp::now(),
-            checksum_sha256: checksum,
-            size_bytes: package.len() as u64,
-        };
-        fs::write(self.version_meta_path(id, &version), serde_json::to_string_pretty(&version_meta)?).await?;
-        fs::write(self.package_path(id, &version), &package).await?;
-
-        info!("Published extension: {}@{}", id, version);
-        Ok(())
+    async fn remove_owner(&self, id: &str, owner: &str) -> Result<Vec<String>> {
+        let _lock = self.lock(id).await?;
+        let mut meta = self.read_extension_meta(id).await?;
+        if !meta.owners.iter().any(|o| o == owner) {
+            return Err(Error::NotFound(format!("Owner {} of {}", owner, id)));
+        }
+        if meta.owners.len() == 1 {
+            return Err(Error::BadRequest(format!("Cannot remove the last owner of {}", id)));
+        }
+        meta.owners.retain(|o| o != owner);
+        self.write_extension_meta(&meta).await?;
+        self.reindex(id).await?;
+        info!("Removed owner {} from {}", owner, id);
+        Ok(meta.owners)
     }
+}
 
-    async fn get_latest_version(&self, id: &str) -> Result<Version> {
-        let versions = self.list_versions(id).await?;
-        let latest = versions.first().ok_or_else(|| Error::NotFound(format!("Extension {}", id)))?;
-        self.read_version_meta(id, latest).await
+/// Whether `entry` was last modified longer than [`STALE_STAGING_AGE`] ago.
+fn is_stale(entry: &std::fs::DirEntry) -> bool {
+    entry.metadata()
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.elapsed().ok())
+        .is_some_and(|age| age > STALE_STAGING_AGE)
+}
+
+/// Remove temporary files that interrupted [`write_atomic`] calls left in `dir`. Recent ones may belong
+/// to a write in progress in another process and are kept.
+fn remove_stale_temp_files(dir: &Path) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let temporary = name.to_str().is_some_and(|n| n.starts_with('.') && n.ends_with(".tmp"));
+        if !temporary || !is_stale(&entry) {
+            continue;
+        }
+        match std::fs::remove_file(entry.path()) {
+            Ok(()) => info!("Removed interrupted write: {}", entry.path().display()),
+            Err(e) => warn!("Failed to remove {}: {}", entry.path().display(), e),
+        }
     }
 }
 
-fn extract_strings(value: &serde_json::Value) -> Vec<String> {
-    value.as_array()
-        .map(|a| a.iter().filter_map(|v| v.as_str().map(String::from)).collect())
-        .unwrap_or_default()
+/// Write `contents` to `path` and flush it to disk.
+async fn write_synced(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut file = fs::File::create(path).await?;
+    file.write_all(contents).await?;
+    file.sync_all().await?;
+    Ok(())
+}
+
+/// Replace `path` with `contents` via a temporary file and rename, so readers never see a partial write.
+pub(crate) async fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let (path, contents) = (path.to_owned(), contents.to_vec());
+    tokio::task::spawn_blocking(move || write_atomic_blocking(&path, &contents))
+        .await
+        .map_err(|e| Error::Internal(e.to_string()))?
+}
+
+/// Blocking version of [`write_atomic`].
+fn write_atomic_blocking(path: &Path, contents: &[u8]) -> Result<()> {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("file");
+    let tmp = path.with_file_name(format!(".{}.{}.tmp", name, hex::encode(rand::random::<[u8; 4]>())));
+    let written = std::fs::File::create(&tmp).and_then(|mut file| {
+        std::io::Write::write_all(&mut file, contents)?;
+        file.sync_all()
+    });
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    std::fs::rename(&tmp, path)?;
+    Ok(())
 }
@@ -0,0 +1,48 @@
+//! In-memory cache of a filesystem index.
+
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+use crate::registry::search::Document;
+use crate::registry::Meta;
+use crate::types::Version;
+
+/// Cached metadata of one extension.
+#[derive(Debug, Clone)]
+pub(super) struct Entry {
+    pub meta: Meta,
+    pub document: Document,
+    /// All versions, newest first.
+    pub versions: Vec<Version>,
+    /// Total downloads in `downloads.json` as of when the entry was loaded. Downloads saved or still
+    /// pending since are tracked by the index.
+    pub downloads: u64,
+}
+
+impl Entry {
+    /// Newest version that has not been yanked.
+    pub fn latest_unyanked(&self) -> Option<&Version> {
+        self.versions.iter().find(|v| !v.yanked)
+    }
+
+    pub fn version(&self, version: &semver::Version) -> Option<&Version> {
+        self.versions.iter().find(|v| &v.version == version)
+    }
+}
+
+/// Marker used to detect changes made by other processes.
+///
+/// Every write bumps the `.generation` file in the registry root; adding or removing an extension
+/// directory by hand changes the modification time of `extensions/`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Stamp {
+    pub generation: Option<String>,
+    pub modified: Option<SystemTime>,
+}
+
+/// Snapshot of every extension in the registry.
+#[derive(Debug)]
+pub(super) struct Cache {
+    pub stamp: Stamp,
+    pub entries: BTreeMap<String, Entry>,
+}
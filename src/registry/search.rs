@@ -0,0 +1,405 @@
+//! Ranked search and listing over extension metadata, shared by the index implementations.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+
+use jiff::Timestamp;
+
+use crate::registry::{latest_compatible, Meta};
+use crate::types::{Filter, FilterField, ListOptions, Page, SortField, SortOrder, Summary, Version};
+
+/// Bonus when the query is exactly the extension id.
+const EXACT_ID: f64 = 100.0;
+/// Bonus when the query is exactly the extension name, ignoring case.
+const EXACT_NAME: f64 = 50.0;
+/// Score of a query term by the field it is found in.
+const ID_TERM: f64 = 10.0;
+const NAME_TERM: f64 = 8.0;
+const KEYWORD_TERM: f64 = 6.0;
+const DESCRIPTION_TERM: f64 = 1.0;
+/// Share of a field's score earned when a term is only the prefix of a word in it.
+const PREFIX_SHARE: f64 = 0.5;
+/// Share of a field's score earned when a term is within a few typos of a word, or of its prefix.
+const FUZZY_SHARE: f64 = 0.25;
+/// Score when the whole query appears inside the id, name or description.
+const SUBSTRING: f64 = 0.5;
+
+/// Split `text` into lowercase alphanumeric words.
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Searchable fields of one extension, tokenized once.
+#[derive(Debug, Clone)]
+pub struct Document {
+    id: String,
+    /// Lowercase name, for exact matches.
+    name: String,
+    id_terms: Vec<String>,
+    name_terms: Vec<String>,
+    keyword_terms: Vec<String>,
+    description_terms: Vec<String>,
+    /// Lowercase id, name and description, for substring matches.
+    text: String,
+}
+
+impl Document {
+    pub fn new(meta: &Meta) -> Self {
+        Self {
+            id: meta.id.clone(),
+            name: meta.name.to_lowercase(),
+            id_terms: tokenize(&meta.id),
+            name_terms: tokenize(&meta.name),
+            keyword_terms: meta.keywords.iter().flat_map(|k| tokenize(k)).collect(),
+            description_terms: tokenize(&meta.description),
+            text: format!("{}\n{}\n{}", meta.id, meta.name, meta.description).to_lowercase(),
+        }
+    }
+}
+
+/// A parsed search query.
+#[derive(Debug, Clone)]
+pub struct Query {
+    text: String,
+    terms: Vec<String>,
+}
+
+impl Query {
+    /// Parse `query`. Blank queries match everything, so they yield `None`.
+    pub fn parse(query: &str) -> Option<Self> {
+        let text = query.trim().to_lowercase();
+        if text.is_empty() {
+            return None;
+        }
+        Some(Self {
+            terms: tokenize(&text),
+            text,
+        })
+    }
+
+    /// Relevance of `document`, or `None` if it does not match.
+    ///
+    /// A document matches if every term is found in one of its fields, as a word, the prefix of a word
+    /// or either of those with a typo or two, or if the whole query appears in its id, name or
+    /// description.
+    pub fn score(&self, document: &Document) -> Option<f64> {
+        let mut score = 0.0;
+        let mut all_terms = !self.terms.is_empty();
+        for term in &self.terms {
+            let term_score = term_score(term, &document.id_terms, ID_TERM)
+                + term_score(term, &document.name_terms, NAME_TERM)
+                + term_score(term, &document.keyword_terms, KEYWORD_TERM)
+                + term_score(term, &document.description_terms, DESCRIPTION_TERM);
+            all_terms &= term_score > 0.0;
+            score += term_score;
+        }
+
+        let substring = document.text.contains(&self.text);
+        if !all_terms && !substring {
+            return None;
+        }
+        if substring {
+            score += SUBSTRING;
+        }
+        if document.id == self.text {
+            score += EXACT_ID;
+        }
+        if document.name == self.text {
+            score += EXACT_NAME;
+        }
+        Some(score)
+    }
+}
+
+fn term_score(term: &str, words: &[String], weight: f64) -> f64 {
+    if words.iter().any(|w| w == term) {
+        return weight;
+    }
+    if words.iter().any(|w| w.starts_with(term)) {
+        return weight * PREFIX_SHARE;
+    }
+    let max_edits = max_edits(term);
+    if max_edits > 0 && words.iter().any(|w| prefix_edit_distance(term, w, max_edits).is_some()) {
+        return weight * FUZZY_SHARE;
+    }
+    0.0
+}
+
+/// Typos tolerated in a term: none for short terms, where almost every word would be a match.
+fn max_edits(term: &str) -> usize {
+    match term.chars().count() {
+        0..=3 => 0,
+        4..=7 => 1,
+        _ => 2,
+    }
+}
+
+/// Smallest Levenshtein distance between `term` and `word` or any prefix of `word`, if it is at most
+/// `max`. Matching prefixes lets partially typed words with a typo still match.
+fn prefix_edit_distance(term: &str, word: &str, max: usize) -> Option<usize> {
+    let term: Vec<char> = term.chars().collect();
+    let word: Vec<char> = word.chars().collect();
+    if word.len() + max < term.len() {
+        return None;
+    }
+
+    // `row[j]` is the distance between the first `i` characters of `term` and the first `j` of `word`.
+    let mut row: Vec<usize> = (0..=word.len()).collect();
+    for (i, &t) in term.iter().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, &w) in word.iter().enumerate() {
+            let substitution = previous + usize::from(t != w);
+            previous = row[j + 1];
+            row[j + 1] = substitution.min(previous + 1).min(row[j] + 1);
+        }
+        if row.iter().all(|&d| d > max) {
+            return None;
+        }
+    }
+    row.into_iter().min().filter(|&d| d <= max)
+}
+
+/// An extension considered for a listing.
+pub(crate) struct Candidate<'a> {
+    pub meta: &'a Meta,
+    pub document: &'a Document,
+    /// Versions ordered newest first. Must include every version if [`needs_all_versions`] says so,
+    /// otherwise the latest unyanked one is enough.
+    pub versions: &'a [Version],
+    pub downloads: u64,
+}
+
+/// Whether listing with `options` looks at other versions than the latest unyanked one.
+pub fn needs_all_versions(options: &ListOptions) -> bool {
+    options.host_version.is_some() || options.sort == Some(SortField::Created)
+}
+
+/// List the candidates matching `options`, sorted and paginated. Ties are broken by id.
+pub(crate) fn list<'a>(candidates: impl IntoIterator<Item = Candidate<'a>>, options: &ListOptions) -> Page<Summary> {
+    let query = options.query.as_deref().and_then(Query::parse);
+    let mut facets = Facets::new(&options.facets);
+    let mut summaries = Vec::new();
+    for candidate in candidates {
+        let meta = candidate.meta;
+        if let Some(ref category) = options.category
+            && !meta.categories.iter().any(|c| c.eq_ignore_ascii_case(category))
+        {
+            continue;
+        }
+        if !options.filters.iter().all(|filter| matches_filter(meta, filter)) {
+            continue;
+        }
+        let Some(version) = latest_compatible(candidate.versions, options.host_version.as_ref()) else {
+            continue;
+        };
+        let score = match query {
+            Some(ref query) => match query.score(candidate.document) {
+                Some(score) => Some(score),
+                None => continue,
+            },
+            None => None,
+        };
+        facets.add(meta);
+        let created = candidate.versions.iter().map(|v| v.created_at).min().unwrap_or(version.created_at);
+        let summary = Summary {
+            downloads: candidate.downloads,
+            score,
+            ..meta.to_summary(version)
+        };
+        summaries.push((summary, created));
+    }
+
+    let field = options.sort.unwrap_or(SortField::Relevance);
+    let order = options.order.unwrap_or(field.default_order());
+    summaries.sort_by(|(a, a_created), (b, b_created)| {
+        let ordering = compare(field, a, a_created, b, b_created);
+        let ordering = match order {
+            SortOrder::Asc => ordering,
+            SortOrder::Desc => ordering.reverse(),
+        };
+        ordering.then_with(|| a.id.cmp(&b.id))
+    });
+    let summaries = summaries.into_iter().map(|(summary, _)| summary).collect();
+    let mut page = paginate(summaries, options.page, options.per_page);
+    if !options.facets.is_empty() {
+        page.facets = Some(facets.finish());
+    }
+    page
+}
+
+/// Values of `field` in `meta`.
+fn field_values(meta: &Meta, field: FilterField) -> &[String] {
+    match field {
+        FilterField::Category => &meta.categories,
+        FilterField::Keyword => &meta.keywords,
+        FilterField::Capability => &meta.capabilities,
+        FilterField::Operation => &meta.operations,
+        FilterField::Author => std::slice::from_ref(&meta.author),
+        FilterField::License => std::slice::from_ref(&meta.license),
+    }
+}
+
+/// Facet counts being collected. Values are counted ignoring case, like filters match them, and
+/// reported with the first spelling seen.
+struct Facets {
+    counts: BTreeMap<FilterField, HashMap<String, (String, u32)>>,
+}
+
+impl Facets {
+    fn new(fields: &[FilterField]) -> Self {
+        Self {
+            counts: fields.iter().map(|&field| (field, HashMap::new())).collect(),
+        }
+    }
+
+    fn add(&mut self, meta: &Meta) {
+        for (&field, counts) in &mut self.counts {
+            let mut seen = Vec::new();
+            for value in field_values(meta, field) {
+                let key = value.to_lowercase();
+                if key.is_empty() || seen.contains(&key) {
+                    continue;
+                }
+                counts.entry(key.clone()).or_insert_with(|| (value.clone(), 0)).1 += 1;
+                seen.push(key);
+            }
+        }
+    }
+
+    fn finish(self) -> BTreeMap<FilterField, BTreeMap<String, u32>> {
+        self.counts
+            .into_iter()
+            .map(|(field, counts)| (field, counts.into_values().collect()))
+            .collect()
+    }
+}
+
+fn matches_filter(meta: &Meta, filter: &Filter) -> bool {
+    let values = field_values(meta, filter.field);
+    filter.values.iter().any(|wanted| values.iter().any(|v| v.eq_ignore_ascii_case(wanted)))
+}
+
+/// Compare two summaries by `field`, in ascending order.
+fn compare(field: SortField, a: &Summary, a_created: &Timestamp, b: &Summary, b_created: &Timestamp) -> Ordering {
+    match field {
+        SortField::Name => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+        SortField::Updated => a.updated_at.cmp(&b.updated_at),
+        SortField::Created => a_created.cmp(b_created),
+        SortField::Downloads => a.downloads.cmp(&b.downloads),
+        SortField::Relevance => a.score.unwrap_or_default().total_cmp(&b.score.unwrap_or_default()),
+    }
+}
+
+/// Cut page `page` out of `items`, with at most 100 items per page.
+pub(crate) fn paginate<T>(items: Vec<T>, page: u32, per_page: u32) -> Page<T> {
+    let total = items.len() as u32;
+    let page = page.max(1);
+    let per_page = per_page.clamp(1, 100);
+    let start = (page as usize - 1) * per_page as usize;
+    let items = items.into_iter().skip(start).take(per_page as usize).collect();
+    Page::new(items, total, page, per_page)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(id: &str, name: &str, keywords: &[&str], description: &str) -> Document {
+        let meta: Meta = serde_json::from_value(serde_json::json!({
+            "id": id,
+            "name": name,
+            "description": description,
+            "author": "",
+            "license": "",
+            "keywords": keywords,
+        }))
+        .unwrap();
+        Document::new(&meta)
+    }
+
+    fn formatter() -> Document {
+        document("json-format", "JSON Formatter", &["pretty-print"], "Formats and validates JSON documents.")
+    }
+
+    fn linter() -> Document {
+        document("yaml-lint", "YAML Lint", &["yaml"], "Lints YAML files and converts them from JSON.")
+    }
+
+    fn score(query: &str, document: &Document) -> Option<f64> {
+        Query::parse(query).unwrap().score(document)
+    }
+
+    #[test]
+    fn edit_distance_to_word_or_prefix() {
+        assert_eq!(prefix_edit_distance("color", "color", 1), Some(0));
+        assert_eq!(prefix_edit_distance("col", "colorize", 0), Some(0));
+        assert_eq!(prefix_edit_distance("colr", "color", 1), Some(1));
+        assert_eq!(prefix_edit_distance("clor", "colorize", 1), Some(1));
+        assert_eq!(prefix_edit_distance("café", "cafe", 1), Some(1));
+    }
+
+    #[test]
+    fn edit_distance_over_limit() {
+        assert_eq!(prefix_edit_distance("abcd", "wxyz", 1), None);
+        assert_eq!(prefix_edit_distance("olcor", "color", 1), None);
+        assert_eq!(prefix_edit_distance("olcor", "color", 2), Some(2));
+        assert_eq!(prefix_edit_distance("formatter", "fmt", 2), None);
+        assert_eq!(prefix_edit_distance("color", "", 1), None);
+    }
+
+    #[test]
+    fn blank_query_matches_everything() {
+        assert!(Query::parse("").is_none());
+        assert!(Query::parse("  ").is_none());
+    }
+
+    #[test]
+    fn exact_id_and_name_rank_first() {
+        assert!(score("json-format", &formatter()).unwrap() >= EXACT_ID);
+        assert!(score("json formatter", &formatter()).unwrap() >= EXACT_NAME);
+        assert_eq!(score("json-format", &linter()), None);
+    }
+
+    #[test]
+    fn fields_are_weighted() {
+        // The formatter has "json" in its id, name and description, the linter only in its description.
+        let in_all = score("json", &formatter()).unwrap();
+        let in_description = score("json", &linter()).unwrap();
+        assert!(in_all > in_description, "{} <= {}", in_all, in_description);
+        assert!(score("pretty", &formatter()).is_some());
+    }
+
+    #[test]
+    fn exact_words_beat_prefixes_beat_typos() {
+        let exact = score("format", &formatter()).unwrap();
+        let prefix = score("forma", &formatter()).unwrap();
+        let typo = score("frmat", &formatter()).unwrap();
+        assert!(exact > prefix && prefix > typo, "{} {} {}", exact, prefix, typo);
+        // Short terms must match exactly or as a prefix.
+        assert_eq!(score("jsn", &formatter()), None);
+    }
+
+    #[test]
+    fn every_term_must_match() {
+        assert_eq!(score("json yaml", &formatter()), None);
+        assert!(score("json yaml", &linter()).is_some());
+        assert_eq!(score("xml", &formatter()), None);
+    }
+
+    #[test]
+    fn matches_non_ascii_words() {
+        let cafe = document("cafe-menu", "Café Menu", &[], "Menus for cafés.");
+        assert!(score("café", &cafe).is_some());
+        assert!(score("Café menu", &cafe).unwrap() >= EXACT_NAME);
+    }
+
+    #[test]
+    fn substring_of_id_matches() {
+        // "son" is neither a word nor a prefix, but the query appears in the id.
+        assert!(score("son-fo", &formatter()).is_some());
+    }
+}
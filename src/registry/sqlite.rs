@@ -0,0 +1,587 @@
+//! SQLite-backed registry implementation.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use tracing::{info, warn};
+
+use crate::package::Manifest;
+use crate::registry::blob::FsBlobStore;
+use crate::registry::downloads::{DownloadCounter, DownloadStore, Downloads};
+use crate::registry::fs::{ExportedExtension, FilesystemRegistry};
+use crate::registry::search::{self, Candidate, Document};
+use crate::registry::{
+    latest_compatible, lock_file, no_latest_version, representative_manifest, validate_id, BlobStore, CompositeRegistry,
+    Index, Meta,
+};
+use crate::types::{Details, ListOptions, Page, PublishOptions, Summary, Version};
+use crate::{Error, Result};
+
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS extensions (
+    id TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    description TEXT NOT NULL,
+    categories TEXT NOT NULL,
+    meta TEXT NOT NULL,
+    latest TEXT,
+    downloads INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE TABLE IF NOT EXISTS versions (
+    extension_id TEXT NOT NULL REFERENCES extensions(id) ON DELETE CASCADE,
+    version TEXT NOT NULL,
+    info TEXT NOT NULL,
+    manifest TEXT,
+    downloads INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (extension_id, version)
+);
+
+CREATE TABLE IF NOT EXISTS owners (
+    extension_id TEXT NOT NULL REFERENCES extensions(id) ON DELETE CASCADE,
+    owner TEXT NOT NULL,
+    PRIMARY KEY (extension_id, owner)
+);
+"#;
+
+/// SQLite-backed registry: a [`SqliteIndex`] with packages in any [`BlobStore`].
+pub type SqliteRegistry = CompositeRegistry<SqliteIndex, dyn BlobStore>;
+
+impl SqliteRegistry {
+    /// Open (or create) the registry rooted at `path`, storing packages in `blobs/` next to the database.
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let index = SqliteIndex::open(path.clone())?;
+        let blobs = FsBlobStore::new(path);
+        for (id, info) in index.call_blocking(|conn| load_all_versions(conn))? {
+            if let Err(e) = blobs.adopt_legacy_package(&id, &info.version, &info.checksum_sha256) {
+                warn!("Failed to migrate package of {}@{}: {}", id, info.version, e);
+            }
+        }
+        Ok(Self::from_parts(index, Arc::new(blobs)))
+    }
+
+    /// Open (or create) the registry database in `path`, storing packages in `blobs`.
+    pub fn with_blobs(path: PathBuf, blobs: Arc<dyn BlobStore>) -> Result<Self> {
+        Ok(Self::from_parts(SqliteIndex::open(path)?, blobs))
+    }
+
+    /// Import every extension of a filesystem registry. Packages are copied into this registry's blob
+    /// store unless it already holds them, which is the case when importing in place. Versions that
+    /// already exist here are skipped.
+    pub async fn import(&self, source: &FilesystemRegistry) -> Result<usize> {
+        let mut imported = 0;
+        for extension in source.index().export().await? {
+            let id = extension.meta.id.clone();
+            validate_id(&id)?;
+
+            let _guard = self.lock_blobs().await?;
+            for version in &extension.versions {
+                let hash = &version.info.checksum_sha256;
+                if !self.blobs().exists(hash).await? {
+                    let package = source.blobs().get(hash).await?;
+                    self.blobs().put(hash, package).await?;
+                }
+            }
+
+            let count = self.index().import(extension).await?;
+            info!("Imported {} ({} versions)", id, count);
+            imported += count;
+        }
+        Ok(imported)
+    }
+}
+
+/// SQLite metadata index.
+///
+/// Metadata, versions, owners and download counts live in `registry.db`. Downloads are counted in memory
+/// and added to the database in one transaction per batch, by a background task every
+/// [`FLUSH_INTERVAL`](crate::registry::downloads::FLUSH_INTERVAL) and when the index is dropped.
+pub struct SqliteIndex {
+    conn: Arc<Mutex<Connection>>,
+    /// Same lock file as a filesystem registry in the same directory uses, so importing in place is safe.
+    blobs_lock: PathBuf,
+    /// Parsed metadata and search document of each extension, so listing and suggestions do not
+    /// rebuild them on every request.
+    documents: Arc<Mutex<HashMap<String, Arc<Indexed>>>>,
+    downloads: DownloadCounter<SqliteDownloads>,
+}
+
+/// Adds batches of download counts to the database.
+struct SqliteDownloads {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl DownloadStore for SqliteDownloads {
+    fn save(&self, batch: &mut HashMap<String, Downloads>) -> Result<()> {
+        let mut conn = self.conn.lock().map_err(|_| Error::Internal("Database connection poisoned".into()))?;
+        let tx = conn.transaction()?;
+        for (id, counts) in batch.iter() {
+            for (version, count) in &counts.versions {
+                tx.execute(
+                    "UPDATE versions SET downloads = downloads + ?3 WHERE extension_id = ?1 AND version = ?2",
+                    params![id, version, count],
+                )?;
+            }
+            tx.execute("UPDATE extensions SET downloads = downloads + ?2 WHERE id = ?1", params![id, counts.total])?;
+        }
+        tx.commit()?;
+        batch.clear();
+        Ok(())
+    }
+}
+
+/// Cached search data of one extension, valid while its stored metadata is unchanged.
+struct Indexed {
+    stored: String,
+    meta: Meta,
+    document: Document,
+}
+
+impl SqliteIndex {
+    /// Open (or create) `registry.db` in `path`.
+    pub fn open(path: PathBuf) -> Result<Self> {
+        std::fs::create_dir_all(&path)?;
+        let conn = Connection::open(path.join("registry.db"))?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        conn.busy_timeout(std::time::Duration::from_secs(5))?;
+        conn.execute_batch(SCHEMA)?;
+        let conn = Arc::new(Mutex::new(conn));
+        Ok(Self {
+            downloads: DownloadCounter::new(SqliteDownloads { conn: conn.clone() }),
+            conn,
+            blobs_lock: path.join(".locks").join(".blobs.lock"),
+            documents: Arc::default(),
+        })
+    }
+
+    fn call_blocking<T>(&self, f: impl FnOnce(&mut Connection) -> Result<T>) -> Result<T> {
+        let mut conn = self.conn.lock().map_err(|_| Error::Internal("Database connection poisoned".into()))?;
+        f(&mut conn)
+    }
+
+    /// Run `f` against the database on the blocking thread pool.
+    async fn call<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().map_err(|_| Error::Internal("Database connection poisoned".into()))?;
+            f(&mut conn)
+        })
+        .await
+        .map_err(|e| Error::Internal(e.to_string()))?
+    }
+
+    /// Insert an exported extension and its versions. Returns the number of new versions.
+    async fn import(&self, extension: ExportedExtension) -> Result<usize> {
+        let meta = extension.meta;
+        let downloads = extension.downloads;
+        let versions: Vec<(Version, Option<String>)> = extension
+            .versions
+            .into_iter()
+            .map(|v| Ok((v.info, v.manifest.map(|m| serde_json::to_string(&m)).transpose()?)))
+            .collect::<Result<_>>()?;
+        self.call(move |conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            insert_extension(&tx, &meta)?;
+            for owner in &meta.owners {
+                tx.execute(
+                    "INSERT OR IGNORE INTO owners (extension_id, owner) VALUES (?1, ?2)",
+                    params![meta.id, owner],
+                )?;
+            }
+            let mut count = 0;
+            for (info, manifest) in &versions {
+                let version = info.version.to_string();
+                let version_downloads = downloads.versions.get(&version).copied().unwrap_or(0);
+                count += tx.execute(
+                    "INSERT OR IGNORE INTO versions (extension_id, version, info, manifest, downloads) VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![meta.id, version, serde_json::to_string(info)?, manifest, version_downloads],
+                )?;
+            }
+            // Importing again must not count the same downloads twice.
+            tx.execute(
+                "UPDATE extensions SET downloads = MAX(downloads, ?2) WHERE id = ?1",
+                params![meta.id, downloads.total],
+            )?;
+            refresh(&tx, &meta.id)?;
+            tx.commit()?;
+            Ok(count)
+        })
+        .await
+    }
+}
+
+/// Every stored version with its extension id.
+fn load_all_versions(conn: &Connection) -> Result<Vec<(String, Version)>> {
+    let mut stmt = conn.prepare("SELECT extension_id, info FROM versions")?;
+    let rows = stmt
+        .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut versions = Vec::with_capacity(rows.len());
+    for (id, info) in rows {
+        versions.push((id, serde_json::from_str(&info)?));
+    }
+    Ok(versions)
+}
+
+/// Insert an extension row if it does not exist yet. Display columns are filled in by [`refresh`].
+fn insert_extension(conn: &Connection, meta: &Meta) -> Result<()> {
+    let stored = Meta { owners: Vec::new(), ..meta.clone() };
+    conn.execute(
+        "INSERT OR IGNORE INTO extensions (id, name, description, categories, meta) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            meta.id,
+            meta.name,
+            meta.description,
+            serde_json::to_string(&meta.categories)?,
+            serde_json::to_string(&stored)?
+        ],
+    )?;
+    Ok(())
+}
+
+fn load_meta(conn: &Connection, id: &str) -> Result<Meta> {
+    let meta: String = conn
+        .query_row("SELECT meta FROM extensions WHERE id = ?1", params![id], |row| row.get(0))
+        .optional()?
+        .ok_or_else(|| Error::NotFound(format!("Extension {}", id)))?;
+    let mut meta: Meta = serde_json::from_str(&meta)?;
+    meta.owners = load_owners(conn, id)?;
+    Ok(meta)
+}
+
+fn load_owners(conn: &Connection, id: &str) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare("SELECT owner FROM owners WHERE extension_id = ?1 ORDER BY rowid")?;
+    let owners = stmt.query_map(params![id], |row| row.get(0))?.collect::<rusqlite::Result<_>>()?;
+    Ok(owners)
+}
+
+/// All versions of `id` with their manifests, newest first.
+fn load_versions(conn: &Connection, id: &str) -> Result<Vec<(Version, Option<Manifest>)>> {
+    let mut stmt = conn.prepare("SELECT info, manifest FROM versions WHERE extension_id = ?1")?;
+    let rows = stmt
+        .query_map(params![id], |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut versions = Vec::with_capacity(rows.len());
+    for (info, manifest) in rows {
+        let manifest = manifest.map(|m| serde_json::from_str(&m)).transpose()?;
+        versions.push((serde_json::from_str::<Version>(&info)?, manifest));
+    }
+    versions.sort_by(|a, b| b.0.version.cmp(&a.0.version));
+    Ok(versions)
+}
+
+fn load_version(conn: &Connection, id: &str, version: &semver::Version) -> Result<Version> {
+    let info: String = conn
+        .query_row(
+            "SELECT info FROM versions WHERE extension_id = ?1 AND version = ?2",
+            params![id, version.to_string()],
+            |row| row.get(0),
+        )
+        .optional()?
+        .ok_or_else(|| Error::VersionNotFound {
+            id: id.to_string(),
+            version: version.to_string(),
+        })?;
+    Ok(serde_json::from_str(&info)?)
+}
+
+/// Version records of `id` without their manifests, newest first.
+fn load_version_infos(conn: &Connection, id: &str) -> Result<Vec<Version>> {
+    let mut stmt = conn.prepare("SELECT info FROM versions WHERE extension_id = ?1")?;
+    let rows = stmt
+        .query_map(params![id], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut versions = rows.iter().map(|info| serde_json::from_str::<Version>(info)).collect::<serde_json::Result<Vec<_>>>()?;
+    versions.sort_by(|a, b| b.version.cmp(&a.version));
+    Ok(versions)
+}
+
+/// Recompute the extension-level metadata and latest unyanked version of `id` from its versions.
+fn refresh(conn: &Connection, id: &str) -> Result<()> {
+    let versions = load_versions(conn, id)?;
+    let latest = versions.iter().find(|(v, _)| !v.yanked).map(|(v, _)| serde_json::to_string(v)).transpose()?;
+
+    let manifest = representative_manifest(versions.into_iter().filter_map(|(v, m)| Some((v, m?))));
+    let meta = match manifest {
+        Some(manifest) => Meta::from_manifest(&manifest, Vec::new()),
+        None => Meta { owners: Vec::new(), ..load_meta(conn, id)? },
+    };
+
+    conn.execute(
+        "UPDATE extensions SET name = ?2, description = ?3, categories = ?4, meta = ?5, latest = ?6 WHERE id = ?1",
+        params![
+            id,
+            meta.name,
+            meta.description,
+            serde_json::to_string(&meta.categories)?,
+            serde_json::to_string(&meta)?,
+            latest
+        ],
+    )?;
+    Ok(())
+}
+
+/// Check ownership and immutability for a publish, creating the extension and its first owner if it
+/// is new. Returns whether an existing version will be replaced.
+fn check_publisher(conn: &Connection, manifest: &Manifest, options: &PublishOptions) -> Result<bool> {
+    let id = &manifest.id;
+    let exists: bool = conn.query_row("SELECT EXISTS (SELECT 1 FROM extensions WHERE id = ?1)", params![id], |row| row.get(0))?;
+    if exists {
+        if !options.admin && !load_owners(conn, id)?.iter().any(|o| o == &options.publisher) {
+            return Err(Error::Forbidden(format!("{} is not an owner of {}", options.publisher, id)));
+        }
+    } else {
+        insert_extension(conn, &Meta::from_manifest(manifest, Vec::new()))?;
+        conn.execute("INSERT INTO owners (extension_id, owner) VALUES (?1, ?2)", params![id, options.publisher])?;
+    }
+
+    let replaced = load_version(conn, id, &manifest.version).is_ok();
+    if replaced && !options.overwrite {
+        return Err(Error::VersionExists {
+            id: id.clone(),
+            version: manifest.version.to_string(),
+        });
+    }
+    Ok(replaced)
+}
+
+#[async_trait]
+impl Index for SqliteIndex {
+    async fn list(&self, options: ListOptions) -> Result<Page<Summary>> {
+        let documents = self.documents.clone();
+        let pending = self.downloads.pending_totals()?;
+        self.call(move |conn| {
+            // Narrow down by category here; search, host compatibility, ranking and sorting are shared
+            // with the filesystem index.
+            let mut stmt = conn.prepare(
+                "SELECT id, meta, latest, downloads FROM extensions WHERE latest IS NOT NULL
+                    AND (?1 IS NULL OR EXISTS (SELECT 1 FROM json_each(categories) WHERE lower(value) = lower(?1)))
+                ORDER BY id",
+            )?;
+            let rows = stmt
+                .query_map(params![options.category], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, u64>(3)?,
+                    ))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+
+            let mut all_versions: HashMap<String, Vec<Version>> = HashMap::new();
+            if search::needs_all_versions(&options) {
+                for (id, version) in load_all_versions(conn)? {
+                    all_versions.entry(id).or_default().push(version);
+                }
+                for versions in all_versions.values_mut() {
+                    versions.sort_by(|a, b| b.version.cmp(&a.version));
+                }
+            }
+
+            let mut documents = documents.lock().map_err(|_| Error::Internal("Document cache poisoned".into()))?;
+            if options.category.is_none() {
+                documents.retain(|id, _| rows.binary_search_by(|row| row.0.as_str().cmp(id)).is_ok());
+            }
+            let mut entries = Vec::with_capacity(rows.len());
+            for (id, stored, latest, downloads) in rows {
+                let indexed = match documents.get(&id) {
+                    Some(indexed) if indexed.stored == stored => indexed.clone(),
+                    _ => {
+                        let meta: Meta = serde_json::from_str(&stored)?;
+                        let indexed = Arc::new(Indexed {
+                            document: Document::new(&meta),
+                            meta,
+                            stored,
+                        });
+                        documents.insert(id.clone(), indexed.clone());
+                        indexed
+                    }
+                };
+                let versions = match all_versions.remove(&id) {
+                    Some(versions) => versions,
+                    None => vec![serde_json::from_str(&latest)?],
+                };
+                entries.push((indexed, versions, downloads));
+            }
+            drop(documents);
+
+            let candidates = entries.iter().map(|(indexed, versions, downloads)| Candidate {
+                meta: &indexed.meta,
+                document: &indexed.document,
+                versions,
+                downloads: *downloads + pending.get(&indexed.meta.id).copied().unwrap_or(0),
+            });
+            Ok(search::list(candidates, &options))
+        })
+        .await
+    }
+
+    async fn get(&self, id: &str) -> Result<Details> {
+        let id = id.to_string();
+        self.call(move |conn| {
+            let meta = load_meta(conn, &id)?;
+            let versions = load_versions(conn, &id)?;
+            let latest = versions
+                .iter()
+                .find(|(v, _)| !v.yanked)
+                .or(versions.first())
+                .map(|(v, _)| v)
+                .ok_or_else(|| Error::NotFound(format!("Extension {}", id)))?;
+            let version_strings = versions.iter().map(|(v, _)| v.version.to_string()).collect();
+            Ok(meta.to_details(latest, version_strings))
+        })
+        .await
+    }
+
+    async fn get_versions(&self, id: &str) -> Result<Vec<Version>> {
+        let id = id.to_string();
+        self.call(move |conn| {
+            let _ = load_meta(conn, &id)?;
+            load_version_infos(conn, &id)
+        })
+        .await
+    }
+
+    async fn get_version(&self, id: &str, version: &semver::Version) -> Result<Version> {
+        let id = id.to_string();
+        let version = version.clone();
+        self.call(move |conn| {
+            let _ = load_meta(conn, &id)?;
+            load_version(conn, &id, &version)
+        })
+        .await
+    }
+
+    async fn check_publish(&self, manifest: &Manifest, options: &PublishOptions) -> Result<()> {
+        let manifest = manifest.clone();
+        let options = options.clone();
+        self.call(move |conn| {
+            // Dropping the transaction rolls back the extension row a first publish creates; it is only
+            // committed together with the version in `insert_version`.
+            let tx = conn.transaction()?;
+            check_publisher(&tx, &manifest, &options).map(|_| ())
+        })
+        .await
+    }
+
+    async fn insert_version(&self, manifest: &Manifest, version: &Version, options: &PublishOptions) -> Result<()> {
+        let manifest = manifest.clone();
+        let info = version.clone();
+        let options = options.clone();
+        self.call(move |conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let id = &manifest.id;
+            if check_publisher(&tx, &manifest, &options)? {
+                warn!("Overwriting existing version: {}@{}", id, info.version);
+            }
+            tx.execute(
+                "INSERT OR REPLACE INTO versions (extension_id, version, info, manifest) VALUES (?1, ?2, ?3, ?4)",
+                params![id, info.version.to_string(), serde_json::to_string(&info)?, serde_json::to_string(&manifest)?],
+            )?;
+            refresh(&tx, id)?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_latest_version(&self, id: &str, host_version: Option<&semver::Version>) -> Result<Version> {
+        let id = id.to_string();
+        if let Some(host_version) = host_version.cloned() {
+            return self
+                .call(move |conn| {
+                    let _ = load_meta(conn, &id)?;
+                    latest_compatible(&load_version_infos(conn, &id)?, Some(&host_version))
+                        .cloned()
+                        .ok_or_else(|| no_latest_version(&id, Some(&host_version)))
+                })
+                .await;
+        }
+        self.call(move |conn| {
+            let latest: Option<String> = conn
+                .query_row("SELECT latest FROM extensions WHERE id = ?1", params![id], |row| row.get(0))
+                .optional()?
+                .flatten();
+            let latest = latest.ok_or_else(|| Error::NotFound(format!("Extension {}", id)))?;
+            Ok(serde_json::from_str(&latest)?)
+        })
+        .await
+    }
+
+    async fn set_yanked(&self, id: &str, version: &semver::Version, yanked: bool, reason: Option<String>) -> Result<Version> {
+        let id = id.to_string();
+        let version = version.clone();
+        self.call(move |conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let mut info = load_version(&tx, &id, &version)?;
+            info.yanked = yanked;
+            info.yank_reason = reason;
+            tx.execute(
+                "UPDATE versions SET info = ?3 WHERE extension_id = ?1 AND version = ?2",
+                params![id, version.to_string(), serde_json::to_string(&info)?],
+            )?;
+            refresh(&tx, &id)?;
+            tx.commit()?;
+            Ok(info)
+        })
+        .await
+    }
+
+    async fn get_owners(&self, id: &str) -> Result<Vec<String>> {
+        let id = id.to_string();
+        self.call(move |conn| Ok(load_meta(conn, &id)?.owners)).await
+    }
+
+    async fn add_owner(&self, id: &str, owner: &str) -> Result<Vec<String>> {
+        let id = id.to_string();
+        let owner = owner.to_string();
+        self.call(move |conn| {
+            let _ = load_meta(conn, &id)?;
+            conn.execute("INSERT OR IGNORE INTO owners (extension_id, owner) VALUES (?1, ?2)", params![id, owner])?;
+            load_owners(conn, &id)
+        })
+        .await
+    }
+
+    async fn remove_owner(&self, id: &str, owner: &str) -> Result<Vec<String>> {
+        let id = id.to_string();
+        let owner = owner.to_string();
+        self.call(move |conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let owners = load_meta(&tx, &id)?.owners;
+            if !owners.iter().any(|o| o == &owner) {
+                return Err(Error::NotFound(format!("Owner {} of {}", owner, id)));
+            }
+            if owners.len() == 1 {
+                return Err(Error::BadRequest(format!("Cannot remove the last owner of {}", id)));
+            }
+            tx.execute("DELETE FROM owners WHERE extension_id = ?1 AND owner = ?2", params![id, owner])?;
+            let owners = load_owners(&tx, &id)?;
+            tx.commit()?;
+            Ok(owners)
+        })
+        .await
+    }
+
+    async fn lock_blobs(&self) -> Result<Option<std::fs::File>> {
+        Ok(Some(lock_file(self.blobs_lock.clone()).await?))
+    }
+
+    async fn all_versions(&self) -> Result<Vec<(String, Version)>> {
+        self.call(|conn| load_all_versions(conn)).await
+    }
+
+    async fn record_download(&self, id: &str, version: &semver::Version) -> Result<()> {
+        self.downloads.record(id, version)
+    }
+}
@@ -0,0 +1,315 @@
+//! Dependency resolution.
+//!
+//! Picks one version per extension so that every requirement, from the request and from the
+//! dependencies of each picked version, is satisfied. Newer versions are preferred; yanked versions are
+//! never picked. The search backtracks when a choice leads to a conflict.
+
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use semver::VersionReq;
+
+use crate::registry::Registry;
+use crate::types::{Resolved, Version};
+use crate::{Error, Result};
+
+/// Upper bound on the number of extensions the search may pick before giving up.
+const MAX_STEPS: usize = 10_000;
+
+/// Who asked for a requirement, for conflict messages.
+const ROOT: &str = "the request";
+
+/// Where the resolver looks up versions. Every [`Registry`] is one.
+#[async_trait]
+pub trait VersionSource: Send + Sync {
+    /// All versions of extension `id`, newest first, or [`Error::NotFound`] if it does not exist.
+    async fn versions(&self, id: &str) -> Result<Vec<Version>>;
+}
+
+#[async_trait]
+impl<R: Registry + ?Sized> VersionSource for R {
+    async fn versions(&self, id: &str) -> Result<Vec<Version>> {
+        self.get_versions(id).await
+    }
+}
+
+/// Resolve `requirements` against the versions in `source`. The result is sorted by id.
+pub async fn resolve<S: VersionSource + ?Sized>(
+    source: &S,
+    requirements: &BTreeMap<String, VersionReq>,
+) -> Result<Vec<Resolved>> {
+    // Load every extension reachable from the roots up front, so the search itself is synchronous.
+    let mut available: BTreeMap<String, Vec<Version>> = BTreeMap::new();
+    let mut queue: Vec<String> = requirements.keys().cloned().collect();
+    while let Some(id) = queue.pop() {
+        if available.contains_key(&id) {
+            continue;
+        }
+        let versions = match source.versions(&id).await {
+            Ok(versions) => versions.into_iter().filter(|v| !v.yanked).collect(),
+            Err(Error::NotFound(_)) => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        for version in &versions {
+            queue.extend(version.dependencies.keys().cloned());
+        }
+        available.insert(id, versions);
+    }
+
+    let mut state = State::default();
+    for (id, req) in requirements {
+        state.constraints.entry(id.clone()).or_default().push((req.clone(), ROOT.to_string()));
+        state.pending.push(id.clone());
+    }
+
+    let mut resolver = Resolver {
+        available: &available,
+        steps: 0,
+        conflict: None,
+    };
+    match resolver.solve(state)? {
+        Some(state) => Ok(state
+            .selected
+            .into_iter()
+            .map(|(id, version)| Resolved {
+                id,
+                version: version.version.clone(),
+                checksum_sha256: version.checksum_sha256.clone(),
+                dependencies: version.dependencies.clone(),
+            })
+            .collect()),
+        None => Err(Error::Unresolvable(
+            resolver.conflict.unwrap_or_else(|| "No consistent set of versions exists".into()),
+        )),
+    }
+}
+
+/// A partial solution.
+#[derive(Debug, Clone, Default)]
+struct State<'a> {
+    selected: BTreeMap<String, &'a Version>,
+    /// Requirements on each extension and who made them.
+    constraints: BTreeMap<String, Vec<(VersionReq, String)>>,
+    /// Extensions that still need a version, in the order they were first required.
+    pending: Vec<String>,
+}
+
+struct Resolver<'a> {
+    /// Unyanked versions of every reachable extension, newest first.
+    available: &'a BTreeMap<String, Vec<Version>>,
+    steps: usize,
+    /// The most recent dead end, reported if the search fails.
+    conflict: Option<String>,
+}
+
+impl<'a> Resolver<'a> {
+    fn solve(&mut self, mut state: State<'a>) -> Result<Option<State<'a>>> {
+        let Some(position) = state.pending.iter().position(|id| !state.selected.contains_key(id)) else {
+            return Ok(Some(state));
+        };
+        let id = state.pending.remove(position);
+
+        self.steps += 1;
+        if self.steps > MAX_STEPS {
+            return Err(Error::Unresolvable(format!(
+                "Gave up after trying {} versions; the last conflict was: {}",
+                MAX_STEPS,
+                self.conflict.as_deref().unwrap_or("none")
+            )));
+        }
+
+        let constraints = state.constraints.get(&id).cloned().unwrap_or_default();
+        let versions = self.available.get(&id).map(Vec::as_slice).unwrap_or_default();
+        let candidates: Vec<&'a Version> = versions
+            .iter()
+            .filter(|v| constraints.iter().all(|(req, _)| req.matches(&v.version)))
+            .collect();
+        if candidates.is_empty() {
+            self.conflict = Some(explain_unsatisfiable(&id, &constraints, versions));
+            return Ok(None);
+        }
+
+        'candidates: for candidate in candidates {
+            let mut next = state.clone();
+            next.selected.insert(id.clone(), candidate);
+            let requirer = format!("{}@{}", id, candidate.version);
+
+            for (dep, req) in &candidate.dependencies {
+                if let Some(chosen) = next.selected.get(dep)
+                    && !req.matches(&chosen.version)
+                {
+                    self.conflict = Some(format!(
+                        "{} requires {} {}, but {}@{} was already chosen{}",
+                        requirer,
+                        dep,
+                        req,
+                        dep,
+                        chosen.version,
+                        describe_requirers(next.constraints.get(dep).map(Vec::as_slice).unwrap_or_default())
+                    ));
+                    continue 'candidates;
+                }
+                next.constraints.entry(dep.clone()).or_default().push((req.clone(), requirer.clone()));
+                next.pending.push(dep.clone());
+            }
+
+            if let Some(solution) = self.solve(next)? {
+                return Ok(Some(solution));
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn explain_unsatisfiable(id: &str, constraints: &[(VersionReq, String)], versions: &[Version]) -> String {
+    let required = constraints
+        .iter()
+        .map(|(req, by)| format!("{} (required by {})", req, by))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if versions.is_empty() {
+        return format!("{} has no published versions, but {} is required", id, required);
+    }
+    let available = versions.iter().map(|v| v.version.to_string()).collect::<Vec<_>>().join(", ");
+    format!("No version of {} satisfies {}; available versions: {}", id, required, available)
+}
+
+fn describe_requirers(constraints: &[(VersionReq, String)]) -> String {
+    if constraints.is_empty() {
+        return String::new();
+    }
+    let requirers = constraints
+        .iter()
+        .map(|(req, by)| format!("{} by {}", req, by))
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!(" for {}", requirers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Versions of each extension, newest first.
+    #[derive(Default)]
+    struct Memory(BTreeMap<String, Vec<Version>>);
+
+    impl Memory {
+        fn add(mut self, id: &str, version: &str, dependencies: &[(&str, &str)]) -> Self {
+            let version = Version {
+                version: version.parse().unwrap(),
+                created_at: jiff::Timestamp::UNIX_EPOCH,
+                checksum_sha256: format!("{}-{}", id, version),
+                size_bytes: 0,
+                yanked: false,
+                yank_reason: None,
+                dependencies: dependencies.iter().map(|(id, req)| (id.to_string(), req.parse().unwrap())).collect(),
+                host: None,
+            };
+            let versions = self.0.entry(id.to_string()).or_default();
+            versions.push(version);
+            versions.sort_by(|a, b| b.version.cmp(&a.version));
+            self
+        }
+
+        fn yank(mut self, id: &str, version: &str) -> Self {
+            let version: semver::Version = version.parse().unwrap();
+            for v in self.0.get_mut(id).unwrap().iter_mut().filter(|v| v.version == version) {
+                v.yanked = true;
+            }
+            self
+        }
+    }
+
+    #[async_trait]
+    impl VersionSource for Memory {
+        async fn versions(&self, id: &str) -> Result<Vec<Version>> {
+            self.0.get(id).cloned().ok_or_else(|| Error::NotFound(id.to_string()))
+        }
+    }
+
+    async fn run(registry: &Memory, requirements: &[(&str, &str)]) -> Result<Vec<(String, String)>> {
+        let requirements = requirements.iter().map(|(id, req)| (id.to_string(), req.parse().unwrap())).collect();
+        let resolved = resolve(registry, &requirements).await?;
+        Ok(resolved.into_iter().map(|r| (r.id, r.version.to_string())).collect())
+    }
+
+    fn picked(versions: &[(&str, &str)]) -> Vec<(String, String)> {
+        versions.iter().map(|(id, v)| (id.to_string(), v.to_string())).collect()
+    }
+
+    #[tokio::test]
+    async fn picks_pinned_version() {
+        let registry = Memory::default().add("a", "1.0.0", &[]).add("a", "1.1.0", &[]);
+        assert_eq!(run(&registry, &[("a", "=1.0.0")]).await.unwrap(), picked(&[("a", "1.0.0")]));
+        assert_eq!(run(&registry, &[("a", "^1")]).await.unwrap(), picked(&[("a", "1.1.0")]));
+    }
+
+    #[tokio::test]
+    async fn resolves_diamond() {
+        let registry = Memory::default()
+            .add("a", "1.0.0", &[("b", "^1"), ("c", "^1")])
+            .add("b", "1.0.0", &[("d", "^1")])
+            .add("c", "1.0.0", &[("d", ">=1.1")])
+            .add("d", "1.0.0", &[])
+            .add("d", "1.1.0", &[])
+            .add("d", "2.0.0", &[]);
+        assert_eq!(
+            run(&registry, &[("a", "*")]).await.unwrap(),
+            picked(&[("a", "1.0.0"), ("b", "1.0.0"), ("c", "1.0.0"), ("d", "1.1.0")])
+        );
+    }
+
+    #[tokio::test]
+    async fn backtracks_out_of_conflict() {
+        // The newest a needs c 2, which b rules out, so a falls back to 1.0.0.
+        let registry = Memory::default()
+            .add("a", "1.0.0", &[("c", "^1")])
+            .add("a", "2.0.0", &[("c", "^2")])
+            .add("b", "1.0.0", &[("c", "^1")])
+            .add("c", "1.0.0", &[])
+            .add("c", "2.0.0", &[]);
+        assert_eq!(
+            run(&registry, &[("a", "*"), ("b", "*")]).await.unwrap(),
+            picked(&[("a", "1.0.0"), ("b", "1.0.0"), ("c", "1.0.0")])
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_unsatisfiable_conflict() {
+        let registry = Memory::default()
+            .add("a", "1.0.0", &[("c", "^1")])
+            .add("b", "1.0.0", &[("c", "^1")])
+            .add("c", "1.0.0", &[])
+            .add("c", "2.0.0", &[]);
+        let err = run(&registry, &[("a", "^1"), ("b", "^1"), ("c", "^2")]).await.unwrap_err();
+        assert!(matches!(err, Error::Unresolvable(_)));
+        assert_eq!(
+            err.to_string(),
+            "Cannot resolve dependencies: No version of c satisfies ^2 (required by the request), \
+             ^1 (required by a@1.0.0), ^1 (required by b@1.0.0); available versions: 2.0.0, 1.0.0"
+        );
+    }
+
+    #[tokio::test]
+    async fn skips_yanked_versions() {
+        let registry = Memory::default().add("a", "1.0.0", &[]).add("a", "1.1.0", &[]).yank("a", "1.1.0");
+        assert_eq!(run(&registry, &[("a", "^1")]).await.unwrap(), picked(&[("a", "1.0.0")]));
+        let err = run(&registry, &[("a", "=1.1.0")]).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot resolve dependencies: No version of a satisfies =1.1.0 (required by the request); \
+             available versions: 1.0.0"
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_missing_dependency() {
+        let registry = Memory::default().add("a", "1.0.0", &[("zzz", "*")]);
+        let err = run(&registry, &[("a", "*")]).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot resolve dependencies: zzz has no published versions, but * (required by a@1.0.0) is required"
+        );
+    }
+}
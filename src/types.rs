@@ -1,8 +1,13 @@
 //! Core types for the extension registry.
 
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
 use jiff::Timestamp;
 use serde::{Deserialize, Serialize};
 
+use crate::Error;
+
 /// A paginated response wrapper.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Page<T> {
@@ -11,12 +16,15 @@ pub struct Page<T> {
     pub page: u32,
     pub per_page: u32,
     pub total_pages: u32,
+    /// Number of matching items with each value of the requested facets, over all pages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub facets: Option<BTreeMap<FilterField, BTreeMap<String, u32>>>,
 }
 
 impl<T> Page<T> {
     pub fn new(items: Vec<T>, total: u32, page: u32, per_page: u32) -> Self {
-        let total_pages = if total == 0 { 1 } else { (total + per_page - 1) / per_page };
-        Self { items, total, page, per_page, total_pages }
+        let total_pages = if total == 0 { 1 } else { total.div_ceil(per_page) };
+        Self { items, total, page, per_page, total_pages, facets: None }
     }
 }
 
@@ -27,12 +35,136 @@ pub struct ListOptions {
     pub query: Option<String>,
     #[serde(default)]
     pub category: Option<String>,
+    /// Metadata filters, all of which must match.
+    #[serde(default)]
+    pub filters: Vec<Filter>,
+    /// Fields to count the values of across all matches, returned in [`Page::facets`].
+    #[serde(default)]
+    pub facets: Vec<FilterField>,
+    /// Only list extensions with a release that supports this host version, summarized at the newest
+    /// such release.
+    #[serde(default)]
+    pub host_version: Option<semver::Version>,
+    /// Field to order by. Defaults to relevance, which orders by id when there is no query.
+    #[serde(default)]
+    pub sort: Option<SortField>,
+    /// Defaults to the natural order of `sort`, see [`SortField::default_order`].
+    #[serde(default)]
+    pub order: Option<SortOrder>,
     #[serde(default = "default_page")]
     pub page: u32,
     #[serde(default = "default_per_page")]
     pub per_page: u32,
 }
 
+/// Extension metadata that listings can be filtered and faceted on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterField {
+    Category,
+    Keyword,
+    Capability,
+    Operation,
+    Author,
+    License,
+}
+
+impl FilterField {
+    /// The field filtered by query parameter `param`, e.g. `capability` for `?capability=`.
+    pub fn from_param(param: &str) -> Option<Self> {
+        match param {
+            "category" => Some(FilterField::Category),
+            "keyword" => Some(FilterField::Keyword),
+            "capability" => Some(FilterField::Capability),
+            "operation" => Some(FilterField::Operation),
+            "author" => Some(FilterField::Author),
+            "license" => Some(FilterField::License),
+            _ => None,
+        }
+    }
+}
+
+/// Matches extensions whose `field` has any of `values`, ignoring case.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Filter {
+    pub field: FilterField,
+    pub values: Vec<String>,
+}
+
+/// Field to order extension listings by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortField {
+    Name,
+    /// When the listed version was published.
+    Updated,
+    /// When the first version was published.
+    Created,
+    Downloads,
+    Relevance,
+}
+
+impl SortField {
+    /// Names sort A-Z; everything else sorts newest, most downloaded or most relevant first.
+    pub fn default_order(self) -> SortOrder {
+        match self {
+            SortField::Name => SortOrder::Asc,
+            _ => SortOrder::Desc,
+        }
+    }
+}
+
+impl FromStr for SortField {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "name" => Ok(SortField::Name),
+            "updated" => Ok(SortField::Updated),
+            "created" => Ok(SortField::Created),
+            "downloads" => Ok(SortField::Downloads),
+            "relevance" => Ok(SortField::Relevance),
+            _ => Err(Error::BadRequest(format!(
+                "Unknown sort {:?}: expected name, updated, created, downloads or relevance",
+                s
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+impl FromStr for SortOrder {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "asc" => Ok(SortOrder::Asc),
+            "desc" => Ok(SortOrder::Desc),
+            _ => Err(Error::BadRequest(format!("Unknown order {:?}: expected asc or desc", s))),
+        }
+    }
+}
+
+/// Options for publishing a package.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PublishOptions {
+    /// Publisher releasing the package; must own the extension if it already exists.
+    pub publisher: String,
+    /// Replace an existing version instead of failing. Breaks clients that pinned the old checksum,
+    /// so only admins may request it.
+    #[serde(default)]
+    pub overwrite: bool,
+    /// Publish on behalf of an admin, who may publish any extension whether or not they own it.
+    #[serde(default)]
+    pub admin: bool,
+}
+
 fn default_page() -> u32 { 1 }
 fn default_per_page() -> u32 { 20 }
 
@@ -48,6 +180,37 @@ pub struct Summary {
     #[serde(default)]
     pub categories: Vec<String>,
     pub updated_at: Timestamp,
+    #[serde(default)]
+    pub downloads: u64,
+    /// Search relevance, set when listing with a query. Higher is better.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
+}
+
+/// A curated category, see [`ExtensionModule::with_categories`](crate::ExtensionModule::with_categories).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Category {
+    /// Value used in manifests and the `?category=` filter.
+    pub id: String,
+    /// Display name.
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+/// A category with the number of listed extensions in it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CategoryCount {
+    #[serde(flatten)]
+    pub category: Category,
+    pub count: u32,
+}
+
+/// An autocomplete suggestion.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Suggestion {
+    pub id: String,
+    pub name: String,
 }
 
 /// Detailed information for an extension.
@@ -85,4 +248,39 @@ pub struct Version {
     pub created_at: Timestamp,
     pub checksum_sha256: String,
     pub size_bytes: u64,
+    /// Yanked versions stay downloadable by exact version but are never picked as latest.
+    #[serde(default)]
+    pub yanked: bool,
+    #[serde(default)]
+    pub yank_reason: Option<String>,
+    /// Extensions this version depends on, from its manifest.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub dependencies: BTreeMap<String, semver::VersionReq>,
+    /// Host versions this version runs on, from its manifest. Any host if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<semver::VersionReq>,
+}
+
+impl Version {
+    /// Whether this version declares support for `host_version`.
+    pub fn supports_host(&self, host_version: &semver::Version) -> bool {
+        self.host.as_ref().is_none_or(|req| req.matches(host_version))
+    }
+}
+
+/// Request body for resolving dependencies.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ResolveRequest {
+    /// Root requirements, by extension id.
+    pub requirements: BTreeMap<String, semver::VersionReq>,
+}
+
+/// A version picked by dependency resolution.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Resolved {
+    pub id: String,
+    pub version: semver::Version,
+    pub checksum_sha256: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub dependencies: BTreeMap<String, semver::VersionReq>,
 }
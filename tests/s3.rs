@@ -0,0 +1,65 @@
+//! Round trip through a real S3-compatible service. Ignored by default; point it at a bucket that
+//! already exists and run it with:
+//!
+//! ```bash
+//! S3_ENDPOINT=http://localhost:9000 S3_BUCKET=extensions AWS_ACCESS_KEY_ID=... AWS_SECRET_ACCESS_KEY=... \
+//!   cargo test --features s3 --test s3 -- --ignored
+//! ```
+//!
+//! Set `S3_REGION` for services that check it and `S3_VIRTUAL_HOSTED=1` to address the bucket by host name.
+
+#![cfg(feature = "s3")]
+
+use std::time::Duration;
+
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+use shopkeep::registry::s3::{S3BlobStore, S3Config};
+use shopkeep::BlobStore;
+use tokio::io::AsyncReadExt;
+
+fn store() -> S3BlobStore {
+    let endpoint = std::env::var("S3_ENDPOINT").expect("S3_ENDPOINT must be set");
+    let bucket = std::env::var("S3_BUCKET").unwrap_or_else(|_| "shopkeep-test".into());
+    let mut config = S3Config::new(endpoint, bucket).with_credentials(
+        std::env::var("AWS_ACCESS_KEY_ID").unwrap_or_default(),
+        std::env::var("AWS_SECRET_ACCESS_KEY").unwrap_or_default(),
+    );
+    config.session_token = std::env::var("AWS_SESSION_TOKEN").ok();
+    config.region = std::env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".into());
+    config.path_style = std::env::var("S3_VIRTUAL_HOSTED").is_err();
+    // A fresh prefix per run, so runs never see each other's objects.
+    config.prefix = format!("shopkeep-test-{}/", hex::encode(rand::random::<[u8; 8]>()));
+    config.presign_expiry = Some(Duration::from_secs(60));
+    S3BlobStore::new(config).unwrap()
+}
+
+#[tokio::test]
+#[ignore = "needs an S3-compatible service, see the module docs"]
+async fn blob_round_trip() {
+    let store = store();
+    let data = Bytes::from_static(b"shopkeep s3 integration test payload");
+    let hash = hex::encode(Sha256::digest(&data));
+
+    assert!(!store.exists(&hash).await.unwrap());
+    store.put(&hash, data.clone()).await.unwrap();
+    store.put(&hash, data.clone()).await.unwrap();
+    assert!(store.exists(&hash).await.unwrap());
+    assert_eq!(store.get(&hash).await.unwrap(), data);
+
+    let mut range = store.stream_range(&hash, 9, 11).await.unwrap();
+    let mut bytes = Vec::new();
+    range.reader.read_to_end(&mut bytes).await.unwrap();
+    assert_eq!(range.length, 2);
+    assert_eq!(bytes, b"s3");
+
+    let url = store.url(&hash).await.unwrap().expect("presigning is enabled");
+    let fetched = reqwest::get(&url).await.unwrap();
+    assert!(fetched.status().is_success(), "presigned GET failed: {}", fetched.status());
+    assert_eq!(fetched.bytes().await.unwrap(), data);
+
+    store.delete(&hash).await.unwrap();
+    store.delete(&hash).await.unwrap();
+    assert!(!store.exists(&hash).await.unwrap());
+    assert!(store.get(&hash).await.is_err());
+}